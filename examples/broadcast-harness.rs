@@ -0,0 +1,390 @@
+//! Offline tuning harness for the `broadcast` node.
+//!
+//! Runs a cluster of real `broadcast` processes behind an in-process router that delays every
+//! message between nodes by a fixed latency, drives a Maelstrom-like workload against it and
+//! reports msgs-per-op and stable latency percentiles for each batching configuration. Replies to
+//! the client are not delayed, so the probes that find out when a value became visible don't add
+//! a round trip to the latencies. Build the node first, then run
+//!
+//!     cargo run --release --example broadcast-harness [name:KEY=VALUE,... ...]
+//!
+//! where each argument is a configuration made of `BROADCAST_*` environment variables (without
+//! the prefix) plus an optional `topology` of `grid` (Maelstrom's default) or `tree`.
+
+use anyhow::Context;
+use rand::prelude::*;
+use serde_json::{json, Value};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+const NODES: usize = 25;
+const LATENCY: Duration = Duration::from_millis(100);
+const RATE: u32 = 100;
+const WORKLOAD: Duration = Duration::from_secs(10);
+// time given to the cluster to converge after the last broadcast
+const SETTLE: Duration = Duration::from_secs(3);
+// how often nodes that are still missing a value are read to find out when it became visible
+// there. a value that shows up in a probe became visible at some point since the previous one, so
+// half an interval is taken off the time it was seen at.
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+const DEFAULT_CONFIGS: &[&str] = &[
+    "fixed-300ms:BATCH_SIZE=1000000,MIN_INTERVAL_MS=300,MAX_INTERVAL_MS=300",
+    "default:",
+    "eager:BATCH_SIZE=1,MIN_INTERVAL_MS=20,MAX_INTERVAL_MS=200",
+    "lazy:BATCH_SIZE=256,MIN_INTERVAL_MS=200,MAX_INTERVAL_MS=1600",
+    "tree:topology=tree",
+    "tree-lazy:topology=tree,MIN_INTERVAL_MS=200,MAX_INTERVAL_MS=1600",
+];
+
+struct Config {
+    name: String,
+    env: Vec<(String, String)>,
+    topology: String,
+}
+
+impl Config {
+    fn parse(spec: &str) -> anyhow::Result<Self> {
+        let (name, settings) = spec.split_once(':').unwrap_or((spec, ""));
+        let mut config = Config {
+            name: name.to_string(),
+            env: Vec::new(),
+            topology: "grid".to_string(),
+        };
+        for setting in settings.split(',').filter(|s| !s.is_empty()) {
+            let (key, value) = setting
+                .split_once('=')
+                .with_context(|| format!("setting {} is not KEY=VALUE", setting))?;
+            if key == "topology" {
+                config.topology = value.to_string();
+            } else {
+                config
+                    .env
+                    .push((format!("BROADCAST_{}", key), value.to_string()));
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn topology(kind: &str, ids: &[String]) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    let mut topology: HashMap<_, Vec<_>> = ids.iter().map(|n| (n.clone(), Vec::new())).collect();
+    let mut link = |a: usize, b: usize| {
+        topology.get_mut(&ids[a]).unwrap().push(ids[b].clone());
+        topology.get_mut(&ids[b]).unwrap().push(ids[a].clone());
+    };
+    match kind {
+        "grid" => {
+            let side = (ids.len() as f64).sqrt().ceil() as usize;
+            for i in 0..ids.len() {
+                if (i + 1) % side != 0 && i + 1 < ids.len() {
+                    link(i, i + 1);
+                }
+                if i + side < ids.len() {
+                    link(i, i + side);
+                }
+            }
+        }
+        "tree" => {
+            for i in 1..ids.len() {
+                link(i, (i - 1) / 4);
+            }
+        }
+        _ => anyhow::bail!("unknown topology {}", kind),
+    }
+    Ok(topology)
+}
+
+enum Input {
+    // a line written by a node, to be routed at the given instant
+    Line(Instant, Value),
+}
+
+struct Cluster {
+    ids: Vec<String>,
+    children: Vec<Child>,
+    stdins: HashMap<String, ChildStdin>,
+    rx: mpsc::Receiver<Input>,
+    // node output waiting for its simulated latency to pass
+    in_flight: BinaryHeap<Reverse<(Instant, u64)>>,
+    queued: HashMap<u64, Value>,
+    seq: u64,
+    server_msgs: usize,
+    next_msg_id: u64,
+}
+
+impl Cluster {
+    fn start(binary: &PathBuf, config: &Config) -> anyhow::Result<Self> {
+        let ids: Vec<_> = (0..NODES).map(|i| format!("n{}", i)).collect();
+        let (tx, rx) = mpsc::channel();
+        let mut children = Vec::new();
+        let mut stdins = HashMap::new();
+        for id in &ids {
+            let mut child = Command::new(binary)
+                .envs(config.env.iter().cloned())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .with_context(|| format!("spawn {}", binary.display()))?;
+            let stdout = BufReader::new(child.stdout.take().expect("piped stdout"));
+            stdins.insert(id.clone(), child.stdin.take().expect("piped stdin"));
+            let tx = tx.clone();
+            let nodes: HashSet<_> = ids.iter().cloned().collect();
+            std::thread::spawn(move || {
+                for line in stdout.lines() {
+                    let Ok(line) = line else { break };
+                    let Ok(msg) = serde_json::from_str::<Value>(&line) else {
+                        break;
+                    };
+                    let to_node = msg["dest"].as_str().is_some_and(|d| nodes.contains(d));
+                    let at = if to_node {
+                        Instant::now() + LATENCY
+                    } else {
+                        Instant::now()
+                    };
+                    if tx.send(Input::Line(at, msg)).is_err() {
+                        break;
+                    }
+                }
+            });
+            children.push(child);
+        }
+
+        let mut cluster = Self {
+            ids,
+            children,
+            stdins,
+            rx,
+            in_flight: BinaryHeap::new(),
+            queued: HashMap::new(),
+            seq: 0,
+            server_msgs: 0,
+            next_msg_id: 1,
+        };
+        for id in cluster.ids.clone() {
+            let node_ids = cluster.ids.clone();
+            cluster.request(&id, json!({"type": "init", "node_id": id, "node_ids": node_ids}))?;
+        }
+        let topology = topology(&config.topology, &cluster.ids)?;
+        for id in cluster.ids.clone() {
+            cluster.request(&id, json!({"type": "topology", "topology": topology}))?;
+        }
+        Ok(cluster)
+    }
+
+    fn request(&mut self, node: &str, mut body: Value) -> anyhow::Result<u64> {
+        let msg_id = self.next_msg_id;
+        self.next_msg_id += 1;
+        body["msg_id"] = json!(msg_id);
+        let msg = json!({"src": "c0", "dest": node, "body": body});
+        self.write(node, &msg)?;
+        Ok(msg_id)
+    }
+
+    fn write(&mut self, node: &str, msg: &Value) -> anyhow::Result<()> {
+        let stdin = self.stdins.get_mut(node).context("unknown node")?;
+        serde_json::to_writer(&mut *stdin, msg)?;
+        stdin.write_all(b"\n")?;
+        Ok(())
+    }
+
+    // routes everything that is due by `until` and returns the client replies seen on the way.
+    fn run_until(&mut self, until: Instant) -> anyhow::Result<Vec<Value>> {
+        let mut replies = Vec::new();
+        loop {
+            let now = Instant::now();
+            while let Some(Reverse((at, seq))) = self.in_flight.peek().copied() {
+                if at > now {
+                    break;
+                }
+                self.in_flight.pop();
+                let msg = self.queued.remove(&seq).expect("queued message");
+                let dest = msg["dest"].as_str().unwrap_or_default().to_string();
+                if self.stdins.contains_key(&dest) {
+                    self.server_msgs += 1;
+                    self.write(&dest, &msg)?;
+                } else {
+                    replies.push(msg);
+                }
+            }
+            if now >= until {
+                return Ok(replies);
+            }
+            let next = self
+                .in_flight
+                .peek()
+                .map_or(until, |Reverse((at, _))| (*at).min(until));
+            match self.rx.recv_timeout(next.saturating_duration_since(now)) {
+                Ok(Input::Line(at, msg)) => {
+                    self.seq += 1;
+                    self.in_flight.push(Reverse((at, self.seq)));
+                    self.queued.insert(self.seq, msg);
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("all nodes exited"),
+            }
+        }
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+struct Report {
+    ops: usize,
+    server_msgs: usize,
+    latencies: Vec<Duration>,
+    lost: usize,
+}
+
+fn run(binary: &PathBuf, config: &Config) -> anyhow::Result<Report> {
+    let mut cluster = Cluster::start(binary, config)?;
+    let mut rng = rand::thread_rng();
+    // let init and topology settle before measuring
+    cluster.run_until(Instant::now() + LATENCY * 3)?;
+    cluster.server_msgs = 0;
+
+    let start = Instant::now();
+    let op_interval = Duration::from_secs(1) / RATE;
+    let mut next_op = start;
+    let mut next_probe = start;
+    let mut ops = 0;
+    let mut next_value = 0u64;
+    let mut broadcast_at: HashMap<u64, Instant> = HashMap::new();
+    // which nodes have been seen to return a value, and when the last of them did
+    let mut seen_by: HashMap<u64, HashSet<usize>> = HashMap::new();
+    let mut stable_at: HashMap<u64, Instant> = HashMap::new();
+    let mut probes: HashMap<u64, usize> = HashMap::new();
+
+    let end = start + WORKLOAD;
+    while Instant::now() < end + SETTLE {
+        let now = Instant::now();
+        if now < end && now >= next_op {
+            let node = cluster.ids[rng.gen_range(0..NODES)].clone();
+            if rng.gen_bool(0.5) {
+                cluster.request(&node, json!({"type": "broadcast", "message": next_value}))?;
+                broadcast_at.insert(next_value, now);
+                next_value += 1;
+            } else {
+                cluster.request(&node, json!({"type": "read"}))?;
+            }
+            ops += 1;
+            next_op += op_interval;
+        }
+        if now >= next_probe {
+            for (i, node) in cluster.ids.clone().iter().enumerate() {
+                let missing = broadcast_at.keys().any(|v| {
+                    !stable_at.contains_key(v) && !seen_by.get(v).is_some_and(|s| s.contains(&i))
+                });
+                if missing {
+                    let msg_id = cluster.request(node, json!({"type": "read"}))?;
+                    probes.insert(msg_id, i);
+                }
+            }
+            next_probe += PROBE_INTERVAL;
+        }
+        let until = if now < end {
+            next_op.min(next_probe)
+        } else {
+            next_probe
+        };
+        for reply in cluster.run_until(until)? {
+            let Some(node) = reply["body"]["in_reply_to"]
+                .as_u64()
+                .and_then(|id| probes.remove(&id))
+            else {
+                continue;
+            };
+            let Some(messages) = reply["body"]["messages"].as_array() else {
+                continue;
+            };
+            let visible_at = Instant::now() - PROBE_INTERVAL / 2;
+            for value in messages.iter().filter_map(Value::as_u64) {
+                let seen = seen_by.entry(value).or_default();
+                if seen.insert(node) && seen.len() == NODES {
+                    let sent = broadcast_at.get(&value).copied().unwrap_or(visible_at);
+                    stable_at.insert(value, visible_at.max(sent));
+                }
+            }
+        }
+    }
+
+    let mut latencies: Vec<_> = stable_at
+        .iter()
+        .filter_map(|(v, at)| broadcast_at.get(v).map(|sent| *at - *sent))
+        .collect();
+    latencies.sort();
+    Ok(Report {
+        ops,
+        server_msgs: cluster.server_msgs,
+        lost: broadcast_at.len() - latencies.len(),
+        latencies,
+    })
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> u128 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    sorted[(sorted.len() - 1) * p / 100].as_millis()
+}
+
+fn main() -> anyhow::Result<()> {
+    let binary = std::env::current_exe()?
+        .parent()
+        .and_then(|examples| examples.parent())
+        .context("locate target directory")?
+        .join("broadcast");
+    anyhow::ensure!(
+        binary.exists(),
+        "{} not found, build the broadcast node first",
+        binary.display()
+    );
+
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    let specs: Vec<&str> = if args.is_empty() {
+        DEFAULT_CONFIGS.to_vec()
+    } else {
+        args.iter().map(String::as_str).collect()
+    };
+
+    println!(
+        "{} nodes, {}ms latency, {} ops/s for {}s",
+        NODES,
+        LATENCY.as_millis(),
+        RATE,
+        WORKLOAD.as_secs()
+    );
+    println!(
+        "{:<12} {:>9} {:>7} {:>7} {:>7} {:>7} {:>5}",
+        "config", "msgs/op", "p50", "p95", "p99", "max", "lost"
+    );
+    for spec in specs {
+        let config = Config::parse(spec)?;
+        let report = run(&binary, &config).with_context(|| format!("run {}", config.name))?;
+        println!(
+            "{:<12} {:>9.2} {:>5}ms {:>5}ms {:>5}ms {:>5}ms {:>5}",
+            config.name,
+            report.server_msgs as f64 / report.ops.max(1) as f64,
+            percentile(&report.latencies, 50),
+            percentile(&report.latencies, 95),
+            percentile(&report.latencies, 99),
+            percentile(&report.latencies, 100),
+            report.lost
+        );
+    }
+    Ok(())
+}
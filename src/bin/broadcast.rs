@@ -1,114 +1,622 @@
 use distributed::*;
 
 use anyhow::Context;
-use rand::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap, HashSet},
+    hash::Hasher,
     io::StdoutLock,
-    time::Duration,
+    str::FromStr,
+    time::{Duration, Instant},
 };
 
+// values whose serialized form is larger than this are only announced by id in gossip and fetched
+// by the nodes that don't have them yet.
+const INLINE_LIMIT: usize = 64;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(1);
+// how often the eventual mode checks whether a neighbor's batch is due. the other modes sync on a
+// fixed, coarser schedule.
+const FLUSH_TICK: Duration = Duration::from_millis(10);
+const SYNC_INTERVAL: Duration = Duration::from_millis(300);
+// the progress table is only used to find values that every node has, so it can lag behind and is
+// sent to each neighbor at most this often.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+type Digest = u64;
+type VectorClock = HashMap<String, u64>;
+// the node a value was first broadcast to (as its position in `node_ids`), and how many values
+// that node had been sent by then.
+type Id = (usize, u64);
+// per origin (in `node_ids` order), the sequence number up to which a node has every value.
+type Progress = Vec<u64>;
+
+// the sequence numbers seen from one origin, kept as the contiguous prefix plus whatever arrived
+// out of order above it. once gossip catches up the set above drains and only the number is left.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Watermark {
+    upto: u64,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    above: BTreeSet<u64>,
+}
+
+impl Watermark {
+    fn contains(&self, seq: u64) -> bool {
+        seq <= self.upto || self.above.contains(&seq)
+    }
+
+    fn insert(&mut self, seq: u64) -> bool {
+        if self.contains(seq) {
+            return false;
+        }
+        self.above.insert(seq);
+        self.compact();
+        true
+    }
+
+    fn raise(&mut self, upto: u64) {
+        if upto > self.upto {
+            self.upto = upto;
+            self.above.retain(|s| *s > upto);
+            self.compact();
+        }
+    }
+
+    fn merge(&mut self, other: &Watermark) {
+        self.raise(other.upto);
+        for seq in &other.above {
+            self.insert(*seq);
+        }
+    }
+
+    fn compact(&mut self) {
+        while self.above.remove(&(self.upto + 1)) {
+            self.upto += 1;
+        }
+    }
+}
+
+// a watermark per origin, in `node_ids` order.
+type Summary = Vec<Watermark>;
+
+fn summary_contains(summary: &Summary, (origin, seq): &Id) -> bool {
+    summary.get(*origin).is_some_and(|w| w.contains(*seq))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    // every node eventually sees every value, reads return them in no particular order.
+    Eventual,
+    // the lowest node id sequences every value into a single log that all nodes replicate, so
+    // reads on every node return prefixes of the same sequence.
+    TotalOrder,
+    // values are stamped with the broadcaster's vector clock and only delivered once everything
+    // they causally depend on has been delivered.
+    Causal,
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "eventual" => Ok(Mode::Eventual),
+            "total-order" => Ok(Mode::TotalOrder),
+            "causal" => Ok(Mode::Causal),
+            _ => anyhow::bail!("unknown broadcast mode {}", s),
+        }
+    }
+}
+
+// hash of a serialized value, to find the values stored already that a new one may be equal to.
+// different values can share a digest, so only comparing the bytes tells them apart.
+fn digest(bytes: &[u8]) -> Digest {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+// when the eventual mode gossips to a neighbor. a batch goes out as soon as `batch_size` new values
+// are waiting for it, or once the neighbor's interval has passed. the interval adapts per neighbor
+// between `min_interval` and `max_interval`: it halves whenever the neighbor sends us something
+// new and doubles whenever it only sends values we already have.
+#[derive(Debug, Clone, Copy)]
+struct Batching {
+    batch_size: usize,
+    min_interval: Duration,
+    max_interval: Duration,
+}
+
+impl Batching {
+    fn from_env() -> anyhow::Result<Self> {
+        fn var(name: &str, default: u64) -> anyhow::Result<u64> {
+            match std::env::var(name) {
+                Ok(v) => v.parse().with_context(|| format!("parse {}", name)),
+                Err(_) => Ok(default),
+            }
+        }
+        let batching = Self {
+            batch_size: var("BROADCAST_BATCH_SIZE", 64)? as usize,
+            min_interval: Duration::from_millis(var("BROADCAST_MIN_INTERVAL_MS", 100)?),
+            max_interval: Duration::from_millis(var("BROADCAST_MAX_INTERVAL_MS", 800)?),
+        };
+        anyhow::ensure!(
+            batching.batch_size > 0 && batching.min_interval <= batching.max_interval,
+            "invalid batching configuration {:?}",
+            batching
+        );
+        Ok(batching)
+    }
+}
+
+struct Config {
+    mode: Mode,
+    batching: Batching,
+}
+
+// what we gossip to a single neighbor in eventual mode.
+struct Link {
+    interval: Duration,
+    last_flush: Instant,
+    // values we sent in the last batch that the neighbor hasn't confirmed yet
+    sent: HashSet<Id>,
+    // the versions of our summary and of the progress table the neighbor was last sent
+    summary_sent: u64,
+    progress_sent: u64,
+    last_progress: Instant,
+}
+
+// how far a node is known to have caught up with every origin, and when that last changed here.
+struct Row {
+    version: u64,
+    progress: Progress,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum Payload {
+enum Payload<T> {
     Broadcast {
-        message: usize,
+        message: T,
     },
     BroadcastOk,
     Read,
     ReadOk {
-        messages: HashSet<usize>,
+        messages: Vec<T>,
     },
     Topology {
         topology: HashMap<String, Vec<String>>,
     },
     TopologyOk,
     Gossip {
-        seen: HashSet<usize>,
+        seen: Vec<(Id, T)>,
+        announced: Vec<Id>,
+        have: Summary,
+        progress: HashMap<String, Progress>,
+    },
+    Fetch {
+        ids: HashSet<Id>,
+    },
+    FetchOk {
+        values: Vec<(Id, T)>,
+    },
+    Sync {
+        have: usize,
+        submit: Vec<(Id, T)>,
+    },
+    SyncOk {
+        from: usize,
+        entries: Vec<(Id, T)>,
     },
+    CausalGossip {
+        delivered: VectorClock,
+        messages: Vec<Stamped<T>>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Stamped<T> {
+    origin: String,
+    clock: VectorClock,
+    value: T,
+}
+
+impl<T> Stamped<T> {
+    fn seq(&self) -> u64 {
+        self.clock.get(&self.origin).copied().unwrap_or_default()
+    }
+
+    // the next value from its origin, and everything the origin had delivered before broadcasting
+    // it has been delivered here too.
+    fn deliverable(&self, delivered: &VectorClock) -> bool {
+        let have = |n: &String| delivered.get(n).copied().unwrap_or_default();
+        self.seq() == have(&self.origin) + 1
+            && self
+                .clock
+                .iter()
+                .all(|(n, c)| n == &self.origin || *c <= have(n))
+    }
 }
 
 enum InjectedPayload {
     Gossip,
 }
 
-struct BroadcastNode {
+struct BroadcastNode<T> {
     node: String,
     id: usize,
-    messages: HashSet<usize>,
-    known: HashMap<String, HashSet<usize>>,
+    mode: Mode,
+    sequencer: String,
+    // in total-order mode every broadcast keeps the id it got from the node it was sent to, so
+    // the same value broadcast twice is logged twice while resubmissions of one broadcast are not.
+    log: Vec<(Id, T)>,
+    logged: HashSet<Id>,
+    // values broadcast to us in total-order mode that the sequencer hasn't put in the log yet
+    pending: BTreeMap<Id, T>,
+    delivered: VectorClock,
+    causal_log: Vec<Stamped<T>>,
+    hold_back: Vec<Stamped<T>>,
+    delivered_by: HashMap<String, VectorClock>,
+    index: usize,
+    next_seq: u64,
+    // every value once, however many ids it was broadcast under, with the positions of the values
+    // of each digest
+    messages: Vec<T>,
+    by_digest: HashMap<Digest, Vec<usize>>,
+    large: HashSet<usize>,
+    // values that haven't reached every node yet, by their position in `messages`. once they have,
+    // they're only kept in `messages` and all per-peer bookkeeping about them collapses into the
+    // watermarks.
+    in_flight: HashMap<Id, usize>,
+    have: Summary,
+    have_version: u64,
+    known: HashMap<String, Summary>,
+    rows: HashMap<String, Row>,
+    rows_version: u64,
+    stable: Progress,
+    batching: Batching,
+    links: HashMap<String, Link>,
+    // values announced to us that we asked for, and when
+    fetching: HashMap<Id, Instant>,
     neighborhood: Vec<String>,
 }
 
-impl Node<(), Payload, InjectedPayload> for BroadcastNode {
+impl<T> BroadcastNode<T>
+where
+    T: Serialize,
+{
+    fn insert(&mut self, id: Id, value: T) -> bool {
+        let bytes = serde_json::to_vec(&value).expect("serialize broadcast value");
+        let positions = self.by_digest.entry(digest(&bytes)).or_default();
+        let stored = positions.iter().copied().find(|p| {
+            serde_json::to_vec(&self.messages[*p]).expect("serialize broadcast value") == bytes
+        });
+        let position = match stored {
+            Some(p) => p,
+            None => {
+                positions.push(self.messages.len());
+                if bytes.len() > INLINE_LIMIT {
+                    self.large.insert(self.messages.len());
+                }
+                self.messages.push(value);
+                self.messages.len() - 1
+            }
+        };
+        self.record(id, position)
+    }
+
+    // notes that we have the value with the given id, returns whether it is new to us.
+    fn record(&mut self, id: Id, position: usize) -> bool {
+        let Some(watermark) = self.have.get_mut(id.0) else {
+            return false;
+        };
+        if !watermark.insert(id.1) {
+            return false;
+        }
+        let upto = watermark.upto;
+        self.have_version += 1;
+        let row = self.rows.get_mut(&self.node).expect("no progress for ourselves");
+        if row.progress[id.0] != upto {
+            self.rows_version += 1;
+            row.version = self.rows_version;
+            row.progress[id.0] = upto;
+        }
+        if !self.is_stable(&id) {
+            self.in_flight.insert(id, position);
+        }
+        true
+    }
+
+    fn is_stable(&self, (origin, seq): &Id) -> bool {
+        *seq <= self.stable[*origin]
+    }
+
+    // a value is stable once every node has it. from then on nobody needs to be sent it again, so
+    // we forget which peers know it.
+    fn collect_stable(&mut self) {
+        let mut advanced = false;
+        for (origin, stable) in self.stable.iter_mut().enumerate() {
+            let reached = self
+                .rows
+                .values()
+                .map(|row| row.progress[origin])
+                .min()
+                .unwrap_or_default();
+            if reached > *stable {
+                *stable = reached;
+                advanced = true;
+            }
+        }
+        if !advanced {
+            return;
+        }
+        let stable = &self.stable;
+        let is_stable = |(origin, seq): &Id| *seq <= stable[*origin];
+        self.in_flight.retain(|id, _| !is_stable(id));
+        let in_flight: HashSet<_> = self.in_flight.values().collect();
+        self.large.retain(|p| in_flight.contains(p));
+        for link in self.links.values_mut() {
+            link.sent.retain(|id| !is_stable(id));
+        }
+        for summary in self.known.values_mut() {
+            for (watermark, upto) in summary.iter_mut().zip(stable) {
+                watermark.raise(*upto);
+            }
+        }
+    }
+
+    fn append(&mut self, id: Id, value: T) {
+        self.pending.remove(&id);
+        if self.logged.insert(id) {
+            self.log.push((id, value));
+        }
+    }
+
+    fn deliver(&mut self, message: Stamped<T>) {
+        let seq = message.seq();
+        if seq <= self.delivered.get(&message.origin).copied().unwrap_or_default()
+            || self.hold_back.iter().any(|h| h.origin == message.origin && h.seq() == seq)
+        {
+            return;
+        }
+        self.hold_back.push(message);
+        // every delivery may unblock other held back values, so keep going until nothing moves.
+        while let Some(i) = self
+            .hold_back
+            .iter()
+            .position(|h| h.deliverable(&self.delivered))
+        {
+            let message = self.hold_back.swap_remove(i);
+            self.delivered.insert(message.origin.clone(), message.seq());
+            self.causal_log.push(message);
+        }
+    }
+
+    fn sync(&self, output: &mut StdoutLock) -> anyhow::Result<()>
+    where
+        T: Clone,
+    {
+        Message {
+            src: self.node.clone(),
+            dst: self.sequencer.clone(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload: Payload::Sync {
+                    have: self.log.len(),
+                    submit: self.pending.clone().into_iter().collect(),
+                },
+            },
+        }
+        .send(&mut *output)
+        .with_context(|| format!("sync with sequencer {}", self.sequencer))
+    }
+}
+
+impl<T> Node<Config, Payload<T>, InjectedPayload> for BroadcastNode<T>
+where
+    T: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
     fn from_init(
-        _state: (),
+        config: Config,
         init: Init,
-        tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+        tx: std::sync::mpsc::Sender<Event<Payload<T>, InjectedPayload>>,
     ) -> anyhow::Result<Self> {
+        let Config { mode, batching } = config;
+        let tick = match mode {
+            Mode::Eventual => FLUSH_TICK,
+            Mode::TotalOrder | Mode::Causal => SYNC_INTERVAL,
+        };
         std::thread::spawn(move || {
             // generate gossip events
             // TODO: handle EOF signal
             loop {
-                std::thread::sleep(Duration::from_millis(300));
+                std::thread::sleep(tick);
                 if tx.send(Event::Injected(InjectedPayload::Gossip)).is_err() {
                     break;
                 }
             }
         });
 
+        let index = init
+            .node_ids
+            .iter()
+            .position(|nid| nid == &init.node_id)
+            .context("node is not part of the cluster")?;
         Ok(Self {
             node: init.node_id,
             id: 1,
-            messages: HashSet::new(),
+            mode,
+            sequencer: init
+                .node_ids
+                .iter()
+                .min()
+                .expect("cluster without nodes")
+                .clone(),
+            log: Vec::new(),
+            logged: HashSet::new(),
+            pending: BTreeMap::new(),
+            delivered: VectorClock::new(),
+            causal_log: Vec::new(),
+            hold_back: Vec::new(),
+            delivered_by: init
+                .node_ids
+                .iter()
+                .map(|nid| (nid.clone(), VectorClock::new()))
+                .collect(),
+            next_seq: 0,
+            messages: Vec::new(),
+            by_digest: HashMap::new(),
+            large: HashSet::new(),
+            in_flight: HashMap::new(),
+            have: vec![Watermark::default(); init.node_ids.len()],
+            have_version: 0,
             known: init
                 .node_ids
-                .into_iter()
-                .map(|nid| (nid, HashSet::new()))
+                .iter()
+                .map(|nid| (nid.clone(), vec![Watermark::default(); init.node_ids.len()]))
+                .collect(),
+            rows: init
+                .node_ids
+                .iter()
+                .map(|nid| {
+                    let row = Row {
+                        version: 0,
+                        progress: vec![0; init.node_ids.len()],
+                    };
+                    (nid.clone(), row)
+                })
+                .collect(),
+            rows_version: 0,
+            stable: vec![0; init.node_ids.len()],
+            batching,
+            links: init
+                .node_ids
+                .iter()
+                .map(|nid| {
+                    let link = Link {
+                        interval: batching.min_interval,
+                        last_flush: Instant::now(),
+                        sent: HashSet::new(),
+                        summary_sent: 0,
+                        progress_sent: 0,
+                        last_progress: Instant::now(),
+                    };
+                    (nid.clone(), link)
+                })
                 .collect(),
+            index,
+            fetching: HashMap::new(),
             neighborhood: Vec::new(),
         })
     }
 
     fn step(
         &mut self,
-        input: Event<Payload, InjectedPayload>,
+        input: Event<Payload<T>, InjectedPayload>,
         output: &mut StdoutLock,
     ) -> anyhow::Result<()> {
         match input {
             Event::EOF => {}
             Event::Injected(payload) => match payload {
+                InjectedPayload::Gossip if self.mode == Mode::TotalOrder => {
+                    if self.node != self.sequencer {
+                        self.sync(output)?;
+                    }
+                }
+                InjectedPayload::Gossip if self.mode == Mode::Causal => {
+                    for n in &self.neighborhood {
+                        // resend everything n isn't known to have delivered yet. the log is in
+                        // delivery order, so n can deliver most of it without holding it back.
+                        let delivered_by_n = &self.delivered_by[n];
+                        let messages = self
+                            .causal_log
+                            .iter()
+                            .filter(|m| {
+                                m.seq()
+                                    > delivered_by_n.get(&m.origin).copied().unwrap_or_default()
+                            })
+                            .cloned()
+                            .collect();
+                        Message {
+                            src: self.node.clone(),
+                            dst: n.clone(),
+                            body: Body {
+                                id: None,
+                                in_reply_to: None,
+                                payload: Payload::CausalGossip {
+                                    delivered: self.delivered.clone(),
+                                    messages,
+                                },
+                            },
+                        }
+                        .send(&mut *output)
+                        .with_context(|| format!("gossip to {}", n))?;
+                    }
+                }
                 InjectedPayload::Gossip => {
+                    let now = Instant::now();
                     for n in &self.neighborhood {
                         let known_to_n = &self.known[n];
-                        let (already_known, mut notify_of): (HashSet<_>, HashSet<_>) = self
-                            .messages
-                            .iter()
-                            .copied()
-                            .partition(|m| known_to_n.contains(m));
+                        let link = self.links.get_mut(n).expect("neighbor is not in the cluster");
+                        let notify_of = || {
+                            self.in_flight
+                                .keys()
+                                .filter(|id| !summary_contains(known_to_n, id))
+                        };
+                        // values we already sent are only resent once the interval has passed, so
+                        // they don't count towards filling up a batch.
+                        let waiting = notify_of().next().is_some();
+                        let unsent = notify_of().filter(|id| !link.sent.contains(id)).count();
+                        let since_flush = now - link.last_flush;
                         // if we know that n knows m, we don't tell n that _we_ know m, so n will
-                        // send us m for all eternity. so, we include a couple of extra `m`s so
-                        // they gradually know all the things that we know without sending lots of
-                        // extra stuff each time.
-                        // we cap the number of extraneous `m`s we include to be at most 10% of the
-                        // number of `m`s` we _have_ to include to avoid excessive overhead.
-                        let mut rng = rand::thread_rng();
-                        let additional_cap = (10 * notify_of.len() / 100) as u32;
-                        notify_of.extend(already_known.iter().filter(|_| {
-                            rng.gen_ratio(
-                                additional_cap.min(already_known.len() as u32),
-                                already_known.len() as u32,
-                            )
-                        }));
+                        // send us m for all eternity. so n also needs to hear about our summary
+                        // when it changes, even if we have nothing new for it.
+                        let progress_due = link.progress_sent < self.rows_version
+                            && now - link.last_progress >= PROGRESS_INTERVAL;
+                        let due = unsent >= self.batching.batch_size
+                            || (waiting && since_flush >= link.interval)
+                            || (link.summary_sent < self.have_version
+                                && since_flush >= self.batching.max_interval)
+                            || progress_due;
+                        if !due {
+                            continue;
+                        }
+                        // small values are sent inline, large ones are only announced and n fetches
+                        // them from us if it doesn't have them yet.
+                        let notify_of: HashSet<_> = notify_of().copied().collect();
+                        let (announced, seen): (Vec<Id>, Vec<Id>) = notify_of
+                            .iter()
+                            .partition(|id| self.large.contains(&self.in_flight[id]));
+                        let seen = seen
+                            .into_iter()
+                            .map(|id| (id, self.messages[self.in_flight[&id]].clone()))
+                            .collect();
+                        let mut progress = HashMap::new();
+                        if progress_due {
+                            progress = self
+                                .rows
+                                .iter()
+                                .filter(|(_, row)| row.version > link.progress_sent)
+                                .map(|(node, row)| (node.clone(), row.progress.clone()))
+                                .collect();
+                            link.progress_sent = self.rows_version;
+                            link.last_progress = now;
+                        }
+                        link.sent = notify_of;
+                        link.last_flush = now;
+                        link.summary_sent = self.have_version;
                         Message {
                             src: self.node.clone(),
                             dst: n.clone(),
                             body: Body {
                                 id: None,
                                 in_reply_to: None,
-                                payload: Payload::Gossip { seen: notify_of },
+                                payload: Payload::Gossip {
+                                    seen,
+                                    announced,
+                                    have: self.have.clone(),
+                                    progress,
+                                },
                             },
                         }
                         .send(&mut *output)
@@ -119,18 +627,173 @@ impl Node<(), Payload, InjectedPayload> for BroadcastNode {
             Event::Message(input) => {
                 let mut reply = input.into_reply(Some(&mut self.id));
                 match reply.body.payload {
-                    Payload::Gossip { seen } => {
-                        self.known
+                    Payload::Gossip {
+                        seen,
+                        announced,
+                        have,
+                        progress,
+                    } => {
+                        let carried = !seen.is_empty() || !announced.is_empty();
+                        let mut fresh = false;
+                        for (id, value) in seen {
+                            fresh |= self.insert(id, value);
+                        }
+                        let now = Instant::now();
+                        let mut missing = HashSet::new();
+                        for id in announced {
+                            if summary_contains(&self.have, &id) {
+                                continue;
+                            }
+                            let due = self
+                                .fetching
+                                .get(&id)
+                                .is_none_or(|since| now - *since > FETCH_TIMEOUT);
+                            if due {
+                                self.fetching.insert(id, now);
+                                missing.insert(id);
+                            }
+                        }
+                        let known = self
+                            .known
+                            .get_mut(&reply.dst)
+                            .expect("got gossip from unknown node");
+                        for (ours, theirs) in known.iter_mut().zip(&have) {
+                            ours.merge(theirs);
+                        }
+                        let link = self
+                            .links
+                            .get_mut(&reply.dst)
+                            .expect("got gossip from unknown node");
+                        if fresh || !missing.is_empty() {
+                            link.interval = (link.interval / 2).max(self.batching.min_interval);
+                        } else if carried {
+                            link.interval = (link.interval * 2).min(self.batching.max_interval);
+                        }
+                        link.sent.retain(|id| !summary_contains(known, id));
+                        for (node, theirs) in progress {
+                            if node == self.node {
+                                continue;
+                            }
+                            let Some(row) = self.rows.get_mut(&node) else {
+                                continue;
+                            };
+                            let mut changed = false;
+                            for (ours, upto) in row.progress.iter_mut().zip(theirs) {
+                                if upto > *ours {
+                                    *ours = upto;
+                                    changed = true;
+                                }
+                            }
+                            if changed {
+                                self.rows_version += 1;
+                                row.version = self.rows_version;
+                            }
+                        }
+                        self.collect_stable();
+                        if !missing.is_empty() {
+                            reply.body.payload = Payload::Fetch { ids: missing };
+                            reply.send(&mut *output).context("fetch gossiped values")?;
+                        }
+                    }
+                    Payload::Fetch { ids } => {
+                        reply.body.payload = Payload::FetchOk {
+                            values: ids
+                                .into_iter()
+                                .filter_map(|id| {
+                                    let p = self.in_flight.get(&id)?;
+                                    Some((id, self.messages[*p].clone()))
+                                })
+                                .collect(),
+                        };
+                        reply.send(&mut *output).context("reply to fetch")?;
+                    }
+                    Payload::FetchOk { values } => {
+                        for (id, value) in values {
+                            if self.fetching.remove(&id).is_some() {
+                                self.insert(id, value);
+                            }
+                        }
+                        self.collect_stable();
+                    }
+                    Payload::Sync { have, submit } => {
+                        for (id, value) in submit {
+                            self.append(id, value);
+                        }
+                        reply.body.payload = Payload::SyncOk {
+                            from: have,
+                            entries: self.log.get(have..).unwrap_or_default().to_vec(),
+                        };
+                        reply.send(&mut *output).context("reply to sync")?;
+                    }
+                    Payload::SyncOk { from, entries } => {
+                        // only extend the log if the entries pick up where our log ends, stale or
+                        // reordered responses are covered by the next sync.
+                        if let Some(skip) = self.log.len().checked_sub(from) {
+                            for (id, value) in entries.into_iter().skip(skip) {
+                                self.append(id, value);
+                            }
+                        }
+                    }
+                    Payload::CausalGossip {
+                        delivered,
+                        messages,
+                    } => {
+                        let delivered_by = self
+                            .delivered_by
                             .get_mut(&reply.dst)
-                            .expect("got gossip from unknown node")
-                            .extend(seen.iter().copied());
-                        self.messages.extend(seen);
+                            .expect("got gossip from unknown node");
+                        for (n, c) in delivered {
+                            let seq = delivered_by.entry(n).or_default();
+                            *seq = c.max(*seq);
+                        }
+                        for message in messages {
+                            self.deliver(message);
+                        }
+                    }
+                    Payload::Broadcast { message } if self.mode == Mode::Causal => {
+                        // everything we have delivered (and so could have been read by the
+                        // client) happened before this broadcast.
+                        let mut clock = self.delivered.clone();
+                        *clock.entry(self.node.clone()).or_default() += 1;
+                        self.deliver(Stamped {
+                            origin: self.node.clone(),
+                            clock,
+                            value: message,
+                        });
+                        reply.body.payload = Payload::BroadcastOk;
+                        reply.send(&mut *output).context("reply to broadcast")?;
+                    }
+                    Payload::Broadcast { message } if self.mode == Mode::TotalOrder => {
+                        self.next_seq += 1;
+                        let id = (self.index, self.next_seq);
+                        if self.node == self.sequencer {
+                            self.append(id, message);
+                        } else {
+                            self.pending.insert(id, message);
+                            self.sync(output)?;
+                        }
+                        reply.body.payload = Payload::BroadcastOk;
+                        reply.send(&mut *output).context("reply to broadcast")?;
                     }
                     Payload::Broadcast { message } => {
-                        self.messages.insert(message);
+                        self.next_seq += 1;
+                        self.insert((self.index, self.next_seq), message);
+                        self.collect_stable();
                         reply.body.payload = Payload::BroadcastOk;
                         reply.send(&mut *output).context("reply to broadcast")?;
                     }
+                    Payload::Read if self.mode == Mode::Causal => {
+                        reply.body.payload = Payload::ReadOk {
+                            messages: self.causal_log.iter().map(|m| m.value.clone()).collect(),
+                        };
+                        reply.send(&mut *output).context("reply to read")?;
+                    }
+                    Payload::Read if self.mode == Mode::TotalOrder => {
+                        reply.body.payload = Payload::ReadOk {
+                            messages: self.log.iter().map(|(_, value)| value.clone()).collect(),
+                        };
+                        reply.send(&mut *output).context("reply to read")?;
+                    }
                     Payload::Read => {
                         reply.body.payload = Payload::ReadOk {
                             messages: self.messages.clone(),
@@ -153,5 +816,10 @@ impl Node<(), Payload, InjectedPayload> for BroadcastNode {
 }
 
 fn main() -> anyhow::Result<()> {
-    main_loop::<_, BroadcastNode, _, _>(())
+    let mode = match std::env::var("BROADCAST_MODE") {
+        Ok(mode) => mode.parse()?,
+        Err(_) => Mode::Eventual,
+    };
+    let batching = Batching::from_env()?;
+    main_loop::<_, BroadcastNode<serde_json::Value>, _, _>(Config { mode, batching })
 }
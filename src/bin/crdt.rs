@@ -0,0 +1,402 @@
+use crdts::{CmRDT, CvRDT, GSet, LWWReg, MVReg, Map, Orswot, PNCounter};
+use distributed::*;
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::StdoutLock,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(200);
+
+enum Kind {
+    GSet,
+    PNCounter,
+    OrSet,
+    LwwRegister,
+    Map,
+}
+
+impl FromStr for Kind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "g-set" => Ok(Kind::GSet),
+            "pn-counter" => Ok(Kind::PNCounter),
+            "or-set" => Ok(Kind::OrSet),
+            "lww-register" => Ok(Kind::LwwRegister),
+            "map" => Ok(Kind::Map),
+            _ => anyhow::bail!("unknown crdt {}", s),
+        }
+    }
+}
+
+// the client requests that change a CRDT. each type supports some of them and rejects the rest.
+enum Update {
+    Add(Value),
+    Remove(Value),
+    Write(Value),
+    Put(String, Value),
+    Delete(String),
+}
+
+type Rejection = (usize, String);
+
+fn not_supported(kind: &str, supported: &str) -> Rejection {
+    (
+        error_code::NOT_SUPPORTED,
+        format!("{} only supports {}", kind, supported),
+    )
+}
+
+// a state-based CRDT the node can host. the node replicates it by merging whole states, so
+// anything that implements CvRDT plugs in by saying how client requests change it (usually by
+// building and applying a CmRDT op) and how it reads back.
+trait Crdt: CvRDT + Default + Clone + Serialize + DeserializeOwned + Send + 'static {
+    fn update(&mut self, node: &str, update: Update) -> Result<(), Rejection>;
+
+    fn read(&self) -> Value;
+}
+
+// JSON values have no order or hash, so sets and registers hold their canonical encoding.
+fn encode(value: &Value) -> String {
+    value.to_string()
+}
+
+fn decode(element: &str) -> Value {
+    serde_json::from_str(element).expect("elements are encoded JSON")
+}
+
+impl Crdt for GSet<String> {
+    fn update(&mut self, _node: &str, update: Update) -> Result<(), Rejection> {
+        match update {
+            Update::Add(element) => {
+                self.insert(encode(&element));
+                Ok(())
+            }
+            _ => Err(not_supported("g-set", "add")),
+        }
+    }
+
+    fn read(&self) -> Value {
+        self.read().iter().map(|e| decode(e)).collect()
+    }
+}
+
+impl Crdt for PNCounter<String> {
+    fn update(&mut self, node: &str, update: Update) -> Result<(), Rejection> {
+        let Update::Add(delta) = update else {
+            return Err(not_supported("pn-counter", "add"));
+        };
+        let Some(delta) = Integer::from_json(&delta) else {
+            return Err((
+                error_code::MALFORMED_REQUEST,
+                format!("delta {} is not an integer", delta),
+            ));
+        };
+        let Ok(steps) = u64::try_from(delta.0.magnitude()) else {
+            return Err((
+                error_code::MALFORMED_REQUEST,
+                format!("delta {} does not fit in 64 bits", delta.0),
+            ));
+        };
+        let op = if delta.0.sign() == num_bigint::Sign::Minus {
+            self.dec_many(node.to_string(), steps)
+        } else {
+            self.inc_many(node.to_string(), steps)
+        };
+        self.apply(op);
+        Ok(())
+    }
+
+    fn read(&self) -> Value {
+        serde_json::to_value(Integer(self.read())).expect("integers serialize")
+    }
+}
+
+impl Crdt for Orswot<String, String> {
+    fn update(&mut self, node: &str, update: Update) -> Result<(), Rejection> {
+        let op = match update {
+            Update::Add(element) => {
+                let ctx = self.read_ctx().derive_add_ctx(node.to_string());
+                self.add(encode(&element), ctx)
+            }
+            Update::Remove(element) => {
+                let element = encode(&element);
+                let ctx = self.contains(&element).derive_rm_ctx();
+                self.rm(element, ctx)
+            }
+            _ => return Err(not_supported("or-set", "add and remove")),
+        };
+        self.apply(op);
+        Ok(())
+    }
+
+    fn read(&self) -> Value {
+        let mut elements: Vec<_> = self.read().val.into_iter().collect();
+        elements.sort();
+        elements.iter().map(|e| decode(e)).collect()
+    }
+}
+
+// writes are ordered by wall clock, ties broken by node id.
+type Marker = (u64, String);
+
+impl Crdt for LWWReg<Value, Marker> {
+    fn update(&mut self, node: &str, update: Update) -> Result<(), Rejection> {
+        let Update::Write(value) = update else {
+            return Err(not_supported("lww-register", "write"));
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("clock is before the unix epoch")
+            .as_millis() as u64;
+        // never go back behind the write we hold, even if our clock lags behind its writer's
+        let marker = (now.max(self.marker.0 + 1), node.to_string());
+        LWWReg::update(self, value, marker);
+        Ok(())
+    }
+
+    fn read(&self) -> Value {
+        self.val.clone()
+    }
+}
+
+// every key holds a multi-value register, so concurrent puts are all kept until overwritten.
+type Registers = Map<String, MVReg<String, String>, String>;
+
+impl Crdt for Registers {
+    fn update(&mut self, node: &str, update: Update) -> Result<(), Rejection> {
+        let op = match update {
+            Update::Put(key, value) => {
+                let ctx = self.read_ctx().derive_add_ctx(node.to_string());
+                Map::update(self, key, ctx, |reg, ctx| reg.write(encode(&value), ctx))
+            }
+            Update::Delete(key) => {
+                let ctx = self.get(&key).derive_rm_ctx();
+                self.rm(key, ctx)
+            }
+            _ => return Err(not_supported("map", "put and delete")),
+        };
+        self.apply(op);
+        Ok(())
+    }
+
+    fn read(&self) -> Value {
+        self.iter()
+            .map(|entry| {
+                let (key, reg) = entry.val;
+                let mut values = reg.read().val;
+                values.sort();
+                let values = values.iter().map(|v| decode(v)).collect();
+                (key.clone(), values)
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload<C> {
+    // g-set adds an element, pn-counter a delta
+    Add {
+        #[serde(default)]
+        element: Option<Value>,
+        #[serde(default)]
+        delta: Option<Value>,
+    },
+    AddOk,
+    Remove {
+        element: Value,
+    },
+    RemoveOk,
+    Write {
+        value: Value,
+    },
+    WriteOk,
+    Put {
+        key: String,
+        value: Value,
+    },
+    PutOk,
+    Delete {
+        key: String,
+    },
+    DeleteOk,
+    Read,
+    ReadOk {
+        value: Value,
+    },
+    Gossip {
+        version: u64,
+        state: C,
+    },
+    GossipOk {
+        version: u64,
+    },
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+enum InjectedPayload {
+    Gossip,
+}
+
+struct CrdtNode<C> {
+    node: String,
+    id: usize,
+    state: C,
+    // bumped on every local update; peers acknowledge the version they have merged
+    version: u64,
+    acked: HashMap<String, u64>,
+}
+
+impl<C: Crdt> Node<(), Payload<C>, InjectedPayload> for CrdtNode<C> {
+    fn from_init(
+        _state: (),
+        init: Init,
+        tx: std::sync::mpsc::Sender<Event<Payload<C>, InjectedPayload>>,
+    ) -> anyhow::Result<Self> {
+        std::thread::spawn(move || {
+            // generate gossip events
+            // TODO: handle EOF signal
+            loop {
+                std::thread::sleep(GOSSIP_INTERVAL);
+                if tx.send(Event::Injected(InjectedPayload::Gossip)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            node: init.node_id.clone(),
+            id: 1,
+            state: C::default(),
+            version: 0,
+            acked: init
+                .node_ids
+                .into_iter()
+                .filter(|n| n != &init.node_id)
+                .map(|n| (n, 0))
+                .collect(),
+        })
+    }
+
+    fn step(
+        &mut self,
+        input: Event<Payload<C>, InjectedPayload>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        match input {
+            Event::EOF => {}
+            Event::Injected(payload) => match payload {
+                InjectedPayload::Gossip => {
+                    // peers that missed anything get our whole state until they acknowledge it
+                    for (n, acked) in &self.acked {
+                        if *acked >= self.version {
+                            continue;
+                        }
+                        Message {
+                            src: self.node.clone(),
+                            dst: n.clone(),
+                            body: Body {
+                                id: None,
+                                in_reply_to: None,
+                                payload: Payload::Gossip {
+                                    version: self.version,
+                                    state: self.state.clone(),
+                                },
+                            },
+                        }
+                        .send(&mut *output)
+                        .with_context(|| format!("gossip to {}", n))?;
+                    }
+                }
+            },
+            Event::Message(input) => {
+                let mut reply = input.into_reply(Some(&mut self.id));
+                let (update, ok) = match reply.body.payload {
+                    Payload::Gossip { version, state } => {
+                        // a state that conflicts with ours can't be merged, leave it unacknowledged
+                        if self.state.validate_merge(&state).is_ok() {
+                            self.state.merge(state);
+                            reply.body.payload = Payload::GossipOk { version };
+                            reply.send(output).context("acknowledge gossip")?;
+                        }
+                        return Ok(());
+                    }
+                    Payload::GossipOk { version } => {
+                        let acked = self
+                            .acked
+                            .get_mut(&reply.dst)
+                            .expect("got gossip ack from unknown node");
+                        *acked = (*acked).max(version);
+                        return Ok(());
+                    }
+                    Payload::Read => {
+                        reply.body.payload = Payload::ReadOk {
+                            value: self.state.read(),
+                        };
+                        reply.send(output).context("send response to read")?;
+                        return Ok(());
+                    }
+                    Payload::Add { element, delta } => {
+                        let Some(added) = element.or(delta) else {
+                            reply.body.payload = Payload::Error {
+                                code: error_code::MALFORMED_REQUEST,
+                                text: "add needs an element or a delta".to_string(),
+                            };
+                            reply.send(output).context("send response to add")?;
+                            return Ok(());
+                        };
+                        (Update::Add(added), Payload::AddOk)
+                    }
+                    Payload::Remove { element } => (Update::Remove(element), Payload::RemoveOk),
+                    Payload::Write { value } => (Update::Write(value), Payload::WriteOk),
+                    Payload::Put { key, value } => (Update::Put(key, value), Payload::PutOk),
+                    Payload::Delete { key } => (Update::Delete(key), Payload::DeleteOk),
+                    Payload::AddOk
+                    | Payload::RemoveOk
+                    | Payload::WriteOk
+                    | Payload::PutOk
+                    | Payload::DeleteOk
+                    | Payload::ReadOk { .. }
+                    | Payload::Error { .. } => return Ok(()),
+                };
+
+                reply.body.payload = match self.state.update(&self.node, update) {
+                    Ok(()) => {
+                        self.version += 1;
+                        ok
+                    }
+                    Err((code, text)) => Payload::Error { code, text },
+                };
+                reply.send(output).context("send response to update")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let kind = match std::env::var("CRDT") {
+        Ok(kind) => kind.parse()?,
+        Err(_) => Kind::GSet,
+    };
+    match kind {
+        Kind::GSet => main_loop::<_, CrdtNode<GSet<String>>, _, _>(()),
+        Kind::PNCounter => main_loop::<_, CrdtNode<PNCounter<String>>, _, _>(()),
+        Kind::OrSet => main_loop::<_, CrdtNode<Orswot<String, String>>, _, _>(()),
+        Kind::LwwRegister => main_loop::<_, CrdtNode<LWWReg<Value, Marker>>, _, _>(()),
+        Kind::Map => main_loop::<_, CrdtNode<Registers>, _, _>(()),
+    }
+}
@@ -1,48 +1,427 @@
-use crdts::{CmRDT, CvRDT, PNCounter};
+use crdts::CvRDT;
 use distributed::*;
 
 use anyhow::Context;
+use num_bigint::{BigInt, Sign};
 use serde::{Deserialize, Serialize};
-use std::io::StdoutLock;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::StdoutLock,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(200);
+// a peer that hasn't acknowledged anything for this long is assumed to be partitioned away. once
+// it answers again it is sent our full state instead of just the deltas it missed.
+const PARTITION_TIMEOUT: Duration = Duration::from_secs(1);
+// quorum and all-node reads and adds that don't hear from enough replicas within this fail with a
+// timeout.
+const REPLICA_TIMEOUT: Duration = Duration::from_secs(1);
+const SEQ_KV: &str = "seq-kv";
+
+enum Backend {
+    // every node holds a PN-counter and replicates it to its peers.
+    Crdt,
+    // like crdt, but the value never drops below zero.
+    Bounded,
+    // every node keeps its own total in Maelstrom's seq-kv and reads sum up all of them.
+    SeqKv,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "crdt" => Ok(Backend::Crdt),
+            "bounded" => Ok(Backend::Bounded),
+            "seq-kv" => Ok(Backend::SeqKv),
+            _ => anyhow::bail!("unknown counter backend {}", s),
+        }
+    }
+}
+
+// a PN-counter like crdts::PNCounter, but counting in big integers so it never overflows. the
+// increments and decrements of each node only ever grow and merge by taking the maximum.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct BigPNCounter {
+    p: BTreeMap<String, Integer>,
+    n: BTreeMap<String, Integer>,
+}
+
+impl BigPNCounter {
+    // adds delta to the node's count and returns the changed entry as a delta of its own.
+    fn add(&mut self, node: &str, delta: &BigInt) -> BigPNCounter {
+        let mut changed = BigPNCounter::default();
+        let (counts, changed_counts) = match delta.sign() {
+            Sign::Plus => (&mut self.p, &mut changed.p),
+            Sign::Minus => (&mut self.n, &mut changed.n),
+            Sign::NoSign => return changed,
+        };
+        let count = counts.entry(node.to_string()).or_default();
+        count.0 += BigInt::from(delta.magnitude().clone());
+        changed_counts.insert(node.to_string(), count.clone());
+        changed
+    }
+
+    fn read(&self) -> BigInt {
+        let p: BigInt = self.p.values().map(|c| &c.0).sum();
+        let n: BigInt = self.n.values().map(|c| &c.0).sum();
+        p - n
+    }
+}
+
+impl CvRDT for BigPNCounter {
+    type Validation = std::convert::Infallible;
+
+    fn validate_merge(&self, _other: &Self) -> Result<(), Self::Validation> {
+        Ok(())
+    }
+
+    fn merge(&mut self, other: Self) {
+        merge_counts(&mut self.p, other.p);
+        merge_counts(&mut self.n, other.n);
+    }
+}
+
+fn merge_counts(mine: &mut BTreeMap<String, Integer>, theirs: BTreeMap<String, Integer>) {
+    for (node, count) in theirs {
+        let entry = mine.entry(node).or_default();
+        if count > *entry {
+            *entry = count;
+        }
+    }
+}
+
+// a bounded counter (escrow counter): every increment gives the node that made it the right to
+// decrement by as much again, and nodes may hand rights to each other. a node only decrements
+// within its own rights, so however the decrements interleave the value never drops below zero.
+// only the node itself ever changes its counts and the transfers it made, so checking its rights
+// locally is safe.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct BoundedCounter {
+    counts: BigPNCounter,
+    // the rights each node has transferred to every other node, growing only
+    transfers: BTreeMap<String, BTreeMap<String, Integer>>,
+}
+
+impl BoundedCounter {
+    fn add(&mut self, node: &str, delta: &BigInt) -> BoundedCounter {
+        BoundedCounter {
+            counts: self.counts.add(node, delta),
+            transfers: BTreeMap::new(),
+        }
+    }
+
+    // hands amount of node's rights to another node and returns the change as a delta.
+    fn transfer(&mut self, node: &str, to: &str, amount: &BigInt) -> BoundedCounter {
+        let transferred = self.transfers.entry(node.to_string()).or_default();
+        let total = transferred.entry(to.to_string()).or_default();
+        total.0 += amount;
+        let mut changed = BoundedCounter::default();
+        changed
+            .transfers
+            .entry(node.to_string())
+            .or_default()
+            .insert(to.to_string(), total.clone());
+        changed
+    }
+
+    fn rights(&self, node: &str) -> BigInt {
+        let count = |counts: &BTreeMap<String, Integer>| {
+            counts.get(node).map(|c| c.0.clone()).unwrap_or_default()
+        };
+        let received: BigInt = self
+            .transfers
+            .values()
+            .filter_map(|to| to.get(node))
+            .map(|c| &c.0)
+            .sum();
+        let given: BigInt = self
+            .transfers
+            .get(node)
+            .map(|to| to.values().map(|c| &c.0).sum())
+            .unwrap_or_default();
+        count(&self.counts.p) - count(&self.counts.n) + received - given
+    }
+
+    fn read(&self) -> BigInt {
+        self.counts.read()
+    }
+}
+
+impl CvRDT for BoundedCounter {
+    type Validation = std::convert::Infallible;
+
+    fn validate_merge(&self, _other: &Self) -> Result<(), Self::Validation> {
+        Ok(())
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.counts.merge(other.counts);
+        for (node, transferred) in other.transfers {
+            merge_counts(self.transfers.entry(node).or_default(), transferred);
+        }
+    }
+}
+
+// deltas may be JSON numbers or, beyond 64 bits, decimal strings.
+fn parse_delta(delta: &serde_json::Value) -> Result<BigInt, Payload> {
+    Integer::from_json(delta)
+        .map(|d| d.0)
+        .ok_or_else(|| Payload::Error {
+            code: error_code::MALFORMED_REQUEST,
+            text: format!("delta {} is not an integer", delta),
+        })
+}
+
+// how many replicas, ourselves included, a read merges the state of before answering, or an add
+// waits to be stored by before it is acknowledged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Consistency {
+    // just our own: reads may be stale, adds may be lost with the node
+    #[default]
+    Local,
+    // a majority. every quorum read overlaps with every quorum add, so it sees the quorum adds
+    // acknowledged before it started.
+    Quorum,
+    // every replica, so a read sees every add acknowledged before it started, whatever
+    // consistency the add asked for
+    All,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum Payload {
-    Add { delta: i64 },
+    Add {
+        delta: serde_json::Value,
+        #[serde(default)]
+        consistency: Consistency,
+    },
     AddOk,
-    Read,
-    ReadOk { value: i64 },
-    Gossip { json: String },
+    Read {
+        #[serde(default)]
+        consistency: Consistency,
+    },
+    ReadOk {
+        value: Integer,
+    },
+    // the full state is a valid delta too, so full-state syncs use the same message.
+    Gossip {
+        seq: u64,
+        delta: BoundedCounter,
+    },
+    GossipOk {
+        seq: u64,
+    },
+    // asks a peer to hand over some of its rights to decrement. it answers with the transfer it
+    // made, if any.
+    Transfer {
+        amount: Integer,
+    },
+    TransferOk {
+        delta: BoundedCounter,
+    },
+    // asks a peer for its state on behalf of a quorum or all-node read
+    Fetch {
+        read: usize,
+    },
+    FetchOk {
+        read: usize,
+        state: BoundedCounter,
+    },
+    WriteOk,
+    Error {
+        code: usize,
+        text: String,
+    },
 }
 
 enum InjectedPayload {
     Gossip,
+    // the quorum or all-node read or add with this id ran out of time
+    Expire { id: usize },
+}
+
+struct Peer {
+    // the last of our local updates the peer has acknowledged
+    acked: u64,
+    last_ack: Instant,
+    full_sync: bool,
+}
+
+// a quorum or all-node read waiting for the states of its replicas.
+struct ConsistentRead {
+    reply: Message<Payload>,
+    state: BoundedCounter,
+    // replicas still to answer before the read can be served
+    needed: usize,
+}
+
+// a quorum or all-node add waiting for its replicas to acknowledge the gossip carrying it.
+struct ConsistentAdd {
+    reply: Message<Payload>,
+    // the local update that made the add
+    seq: u64,
+    // peers that have to acknowledge it before the add is
+    needed: usize,
 }
 
 struct GrowCounterNode {
     node: String,
     id: usize,
-    counter: PNCounter<String>,
-    others: Vec<String>,
+    // whether decrements are limited to the node's rights
+    bounded: bool,
+    counter: BoundedCounter,
+    // local updates since the last one every peer has acknowledged, and how many there have been
+    delta: BoundedCounter,
+    seq: u64,
+    peers: HashMap<String, Peer>,
+    // when we last asked a peer for rights, we wait for its answer before asking again
+    asked: Option<Instant>,
+    reads: HashMap<usize, ConsistentRead>,
+    adds: HashMap<usize, ConsistentAdd>,
     tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
 }
 
-impl Node<(), Payload, InjectedPayload> for GrowCounterNode {
+impl GrowCounterNode {
+    // the peers that have to answer, besides ourselves.
+    fn needed(&self, consistency: Consistency) -> usize {
+        let replicas = self.peers.len() + 1;
+        match consistency {
+            Consistency::Local => 0,
+            Consistency::Quorum => replicas / 2,
+            Consistency::All => replicas - 1,
+        }
+    }
+
+    // gives up on the read or add with this id once REPLICA_TIMEOUT has passed.
+    fn expire_after_timeout(&self, id: usize) {
+        let tx = self.tx.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(REPLICA_TIMEOUT);
+            let _ = tx.send(Event::Injected(InjectedPayload::Expire { id }));
+        });
+    }
+
+    // sends the updates a peer hasn't acknowledged yet, or our full state after a partition.
+    fn gossip(&mut self, output: &mut StdoutLock) -> anyhow::Result<()> {
+        for (n, peer) in &mut self.peers {
+            let delta = if peer.full_sync {
+                peer.full_sync = false;
+                self.counter.clone()
+            } else if peer.acked < self.seq {
+                self.delta.clone()
+            } else {
+                continue;
+            };
+            Message {
+                src: self.node.clone(),
+                dst: n.clone(),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: Payload::Gossip {
+                        seq: self.seq,
+                        delta,
+                    },
+                },
+            }
+            .send(&mut *output)
+            .with_context(|| format!("gossip to {}", n))?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, changed: BoundedCounter) {
+        self.delta.merge(changed);
+        self.seq += 1;
+    }
+
+    // asks the peer with the most rights we know of to hand over what we are missing.
+    fn ask_for_rights(&mut self, missing: BigInt, output: &mut StdoutLock) -> anyhow::Result<()> {
+        if self
+            .asked
+            .is_some_and(|asked| asked.elapsed() < PARTITION_TIMEOUT)
+        {
+            return Ok(());
+        }
+        let Some((richest, rights)) = self
+            .peers
+            .keys()
+            .map(|n| (n, self.counter.rights(n)))
+            .max_by(|(_, a), (_, b)| a.cmp(b))
+        else {
+            return Ok(());
+        };
+        if rights <= BigInt::default() {
+            return Ok(());
+        }
+        Message {
+            src: self.node.clone(),
+            dst: richest.clone(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload: Payload::Transfer {
+                    amount: Integer(missing),
+                },
+            },
+        }
+        .send(&mut *output)
+        .with_context(|| format!("ask {} for rights", richest))?;
+        self.asked = Some(Instant::now());
+        Ok(())
+    }
+}
+
+impl Node<bool, Payload, InjectedPayload> for GrowCounterNode {
     fn from_init(
-        _state: (),
+        bounded: bool,
         init: Init,
         tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
     ) -> anyhow::Result<Self> {
+        let ticker = tx.clone();
+        std::thread::spawn(move || {
+            // generate gossip events
+            // TODO: handle EOF signal
+            loop {
+                std::thread::sleep(GOSSIP_INTERVAL);
+                if ticker
+                    .send(Event::Injected(InjectedPayload::Gossip))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
         Ok(Self {
             node: init.node_id.clone(),
             id: 1,
-            counter: PNCounter::new(),
-            others: init
+            bounded,
+            counter: BoundedCounter::default(),
+            delta: BoundedCounter::default(),
+            seq: 0,
+            peers: init
                 .node_ids
                 .into_iter()
                 .filter(|n| n != &init.node_id)
+                .map(|n| {
+                    let peer = Peer {
+                        acked: 0,
+                        last_ack: Instant::now(),
+                        full_sync: false,
+                    };
+                    (n, peer)
+                })
                 .collect(),
+            asked: None,
+            reads: HashMap::new(),
+            adds: HashMap::new(),
             tx,
         })
     }
@@ -55,63 +434,434 @@ impl Node<(), Payload, InjectedPayload> for GrowCounterNode {
         match input {
             Event::EOF => {}
             Event::Injected(payload) => match payload {
-                InjectedPayload::Gossip => {
-                    let node_counter =
-                        serde_json::to_string(&self.counter).expect("Serialization error");
-                    for n in &self.others {
-                        Message {
-                            src: self.node.clone(),
-                            dst: n.clone(),
-                            body: Body {
-                                id: None,
-                                in_reply_to: None,
-                                payload: Payload::Gossip {
-                                    json: node_counter.clone(),
-                                },
-                            },
-                        }
-                        .send(&mut *output)
-                        .with_context(|| format!("gossip to {}", n))?;
+                InjectedPayload::Gossip => self.gossip(output)?,
+                InjectedPayload::Expire { id } => {
+                    if let Some(mut read) = self.reads.remove(&id) {
+                        read.reply.body.payload = Payload::Error {
+                            code: error_code::TIMEOUT,
+                            text: format!(
+                                "{} of the replicas needed did not answer in time",
+                                read.needed
+                            ),
+                        };
+                        read.reply.send(&mut *output).context("fail read")?;
+                    }
+                    if let Some(mut add) = self.adds.remove(&id) {
+                        // the add stays applied here and gossip still spreads it, so whether it
+                        // counts is unknown
+                        add.reply.body.payload = Payload::Error {
+                            code: error_code::TIMEOUT,
+                            text: format!(
+                                "{} of the replicas needed did not store the add in time",
+                                add.needed
+                            ),
+                        };
+                        add.reply.send(&mut *output).context("fail add")?;
                     }
                 }
             },
             Event::Message(input) => {
                 let mut reply = input.into_reply(Some(&mut self.id));
                 match reply.body.payload {
-                    Payload::Gossip { json } => {
-                        let other_counter: PNCounter<String> = serde_json::from_str(&json).unwrap();
-                        self.counter.merge(other_counter);
+                    Payload::Gossip { seq, delta } => {
+                        self.counter.merge(delta);
+                        reply.body.payload = Payload::GossipOk { seq };
+                        reply.send(output).context("acknowledge gossip")?;
                     }
-                    Payload::Add { delta } => {
-                        if delta != 0 {
-                            if delta > 0 {
-                                self.counter
-                                    .apply(self.counter.inc_many(self.node.clone(), delta as u64));
-                            } else {
-                                self.counter.apply(
-                                    self.counter.dec_many(self.node.clone(), (-delta) as u64),
-                                );
-                            }
-                            self.tx.send(Event::Injected(InjectedPayload::Gossip))?;
+                    Payload::GossipOk { seq } => {
+                        let now = Instant::now();
+                        let peer = self
+                            .peers
+                            .get_mut(&reply.dst)
+                            .expect("got gossip ack from unknown node");
+                        peer.full_sync |= now - peer.last_ack > PARTITION_TIMEOUT;
+                        peer.last_ack = now;
+                        peer.acked = peer.acked.max(seq);
+                        if self.peers.values().all(|p| p.acked >= self.seq) {
+                            self.delta = BoundedCounter::default();
                         }
 
-                        reply.body.payload = Payload::AddOk;
+                        let stored: Vec<_> = self
+                            .adds
+                            .iter()
+                            .filter(|(_, add)| {
+                                let acked = self.peers.values().filter(|p| p.acked >= add.seq);
+                                acked.count() >= add.needed
+                            })
+                            .map(|(id, _)| *id)
+                            .collect();
+                        for id in stored {
+                            let add = self.adds.remove(&id).expect("listed");
+                            add.reply
+                                .send(&mut *output)
+                                .context("send response to add")?;
+                        }
+                    }
+                    Payload::Add {
+                        ref delta,
+                        consistency,
+                    } => {
+                        reply.body.payload = match parse_delta(delta) {
+                            Ok(delta) => {
+                                let rights = self.counter.rights(&self.node);
+                                if self.bounded && delta.sign() == Sign::Minus && rights < -&delta {
+                                    let missing = -&delta - &rights;
+                                    self.ask_for_rights(missing, output)?;
+                                    Payload::Error {
+                                        code: error_code::PRECONDITION_FAILED,
+                                        text: format!(
+                                            "{} can only decrement by {}",
+                                            self.node, rights
+                                        ),
+                                    }
+                                } else {
+                                    let changed = self.counter.add(&self.node, &delta);
+                                    self.apply(changed);
+                                    Payload::AddOk
+                                }
+                            }
+                            Err(error) => error,
+                        };
+                        let needed = self.needed(consistency);
+                        if needed > 0 && matches!(reply.body.payload, Payload::AddOk) {
+                            let id = self.id;
+                            self.id += 1;
+                            let seq = self.seq;
+                            self.adds.insert(id, ConsistentAdd { reply, seq, needed });
+                            self.expire_after_timeout(id);
+                            return self.gossip(output);
+                        }
                         reply.send(output).context("send response to add")?;
                     }
-                    Payload::Read => {
-                        reply.body.payload = Payload::ReadOk {
-                            value: self.counter.read().to_string().parse::<i64>().unwrap(),
+                    Payload::Transfer { amount } => {
+                        let rights = self.counter.rights(&self.node);
+                        let amount = amount.0.min(rights);
+                        let mut changed = BoundedCounter::default();
+                        if amount > BigInt::default() {
+                            changed = self.counter.transfer(&self.node, &reply.dst, &amount);
+                            self.apply(changed.clone());
+                        }
+                        reply.body.payload = Payload::TransferOk { delta: changed };
+                        reply.send(output).context("send response to transfer")?;
+                    }
+                    Payload::TransferOk { delta } => {
+                        self.counter.merge(delta);
+                        self.asked = None;
+                    }
+                    Payload::Read { consistency } => {
+                        let needed = self.needed(consistency);
+                        if needed == 0 {
+                            reply.body.payload = Payload::ReadOk {
+                                value: Integer(self.counter.read()),
+                            };
+                            reply.send(output).context("send response to read")?;
+                            return Ok(());
+                        }
+
+                        let read = self.id;
+                        self.id += 1;
+                        for n in self.peers.keys() {
+                            Message {
+                                src: self.node.clone(),
+                                dst: n.clone(),
+                                body: Body {
+                                    id: None,
+                                    in_reply_to: None,
+                                    payload: Payload::Fetch { read },
+                                },
+                            }
+                            .send(&mut *output)
+                            .with_context(|| format!("fetch state from {}", n))?;
+                        }
+                        self.reads.insert(
+                            read,
+                            ConsistentRead {
+                                reply,
+                                state: self.counter.clone(),
+                                needed,
+                            },
+                        );
+                        self.expire_after_timeout(read);
+                    }
+                    Payload::Fetch { read } => {
+                        reply.body.payload = Payload::FetchOk {
+                            read,
+                            state: self.counter.clone(),
+                        };
+                        reply.send(output).context("send state")?;
+                    }
+                    Payload::FetchOk { read, state } => {
+                        // what the replicas know is as good as gossip, keep it either way
+                        self.counter.merge(state.clone());
+                        let Some(pending) = self.reads.get_mut(&read) else {
+                            return Ok(());
                         };
-                        reply.send(output).context("send response to read")?;
+                        pending.state.merge(state);
+                        pending.needed -= 1;
+                        if pending.needed == 0 {
+                            let mut pending = self.reads.remove(&read).expect("read exists");
+                            pending.reply.body.payload = Payload::ReadOk {
+                                value: Integer(pending.state.read()),
+                            };
+                            pending
+                                .reply
+                                .send(output)
+                                .context("send response to read")?;
+                        }
+                    }
+                    Payload::AddOk
+                    | Payload::ReadOk { .. }
+                    | Payload::WriteOk
+                    | Payload::Error { .. } => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// a client read waiting for the totals of every node.
+struct PendingRead {
+    reply: Message<Payload>,
+    remaining: usize,
+    sum: BigInt,
+}
+
+struct SeqKvCounterNode {
+    node: String,
+    id: usize,
+    node_ids: Vec<String>,
+    // our total, including adds that haven't been written yet
+    total: BigInt,
+    // add_oks to send once the write carrying their delta is acknowledged, keyed by that write
+    writing: Option<(usize, Vec<Message<Payload>>)>,
+    waiting: Vec<Message<Payload>>,
+    // seq-kv is only sequentially consistent, so a read could see an old total of any node. we
+    // first write a fresh value of our own, after which our reads can't go back behind it.
+    syncs: HashMap<usize, usize>,
+    read_keys: HashMap<usize, (usize, String)>,
+    reads: HashMap<usize, PendingRead>,
+}
+
+impl SeqKvCounterNode {
+    fn key(node: &str) -> String {
+        format!("counter-{}", node)
+    }
+
+    fn request(
+        &mut self,
+        request: KvRequest<Integer>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<usize> {
+        let msg_id = self.id;
+        self.id += 1;
+        Message {
+            src: self.node.clone(),
+            dst: SEQ_KV.to_string(),
+            body: Body {
+                id: Some(msg_id),
+                in_reply_to: None,
+                payload: request,
+            },
+        }
+        .send(&mut *output)
+        .context("send request to seq-kv")?;
+        Ok(msg_id)
+    }
+
+    // writes our total if no other write is in flight, the writes have to land in order.
+    fn flush(&mut self, output: &mut StdoutLock) -> anyhow::Result<()> {
+        if self.writing.is_some() || self.waiting.is_empty() {
+            return Ok(());
+        }
+        let msg_id = self.request(
+            KvRequest::Write {
+                key: Self::key(&self.node),
+                value: Integer(self.total.clone()),
+            },
+            output,
+        )?;
+        self.writing = Some((msg_id, std::mem::take(&mut self.waiting)));
+        Ok(())
+    }
+
+    // the totals are only read once this write is acknowledged, if it fails it is sent again.
+    fn sync(&mut self, read: usize, output: &mut StdoutLock) -> anyhow::Result<()> {
+        let sync = self.request(
+            KvRequest::Write {
+                key: format!("sync-{}", self.node),
+                value: Integer(read.into()),
+            },
+            output,
+        )?;
+        self.syncs.insert(sync, read);
+        Ok(())
+    }
+
+    fn read_totals(&mut self, read: usize, output: &mut StdoutLock) -> anyhow::Result<()> {
+        for n in self.node_ids.clone() {
+            self.read_total(read, n, output)?;
+        }
+        Ok(())
+    }
+
+    fn read_total(
+        &mut self,
+        read: usize,
+        node: String,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let msg_id = self.request(
+            KvRequest::Read {
+                key: Self::key(&node),
+            },
+            output,
+        )?;
+        self.read_keys.insert(msg_id, (read, node));
+        Ok(())
+    }
+
+    fn total_read(
+        &mut self,
+        msg_id: usize,
+        total: BigInt,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let Some((read, _)) = self.read_keys.remove(&msg_id) else {
+            return Ok(());
+        };
+        let pending = self.reads.get_mut(&read).expect("read of unknown request");
+        pending.sum += total;
+        pending.remaining -= 1;
+        if pending.remaining == 0 {
+            let mut pending = self.reads.remove(&read).expect("read of unknown request");
+            pending.reply.body.payload = Payload::ReadOk {
+                value: Integer(pending.sum),
+            };
+            pending
+                .reply
+                .send(output)
+                .context("send response to read")?;
+        }
+        Ok(())
+    }
+}
+
+impl Node<(), Payload, InjectedPayload> for SeqKvCounterNode {
+    fn from_init(
+        _state: (),
+        init: Init,
+        _tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            node: init.node_id,
+            id: 1,
+            node_ids: init.node_ids,
+            total: BigInt::default(),
+            writing: None,
+            waiting: Vec::new(),
+            syncs: HashMap::new(),
+            read_keys: HashMap::new(),
+            reads: HashMap::new(),
+        })
+    }
+
+    fn step(
+        &mut self,
+        input: Event<Payload, InjectedPayload>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let Event::Message(input) = input else {
+            return Ok(());
+        };
+        let in_reply_to = input.body.in_reply_to;
+        let mut reply = input.into_reply(Some(&mut self.id));
+        match reply.body.payload {
+            // adds are only acknowledged once seq-kv has them, whatever consistency they ask for
+            Payload::Add { ref delta, .. } => match parse_delta(delta) {
+                Ok(delta) => {
+                    self.total += delta;
+                    reply.body.payload = Payload::AddOk;
+                    self.waiting.push(reply);
+                    self.flush(output)?;
+                }
+                Err(error) => {
+                    reply.body.payload = error;
+                    reply.send(output).context("send response to add")?;
+                }
+            },
+            // reads always see the latest totals here, whatever consistency they ask for
+            Payload::Read { .. } => {
+                let read = self.id;
+                self.sync(read, output)?;
+                self.reads.insert(
+                    read,
+                    PendingRead {
+                        reply,
+                        remaining: self.node_ids.len(),
+                        sum: BigInt::default(),
+                    },
+                );
+            }
+            Payload::WriteOk => {
+                let Some(in_reply_to) = in_reply_to else {
+                    return Ok(());
+                };
+                if let Some(read) = self.syncs.remove(&in_reply_to) {
+                    self.read_totals(read, output)?;
+                } else if let Some((_, acked)) =
+                    self.writing.take_if(|(msg_id, _)| *msg_id == in_reply_to)
+                {
+                    for reply in acked {
+                        reply.send(output).context("send response to add")?;
                     }
-                    Payload::AddOk | Payload::ReadOk { .. } => {}
+                    self.flush(output)?;
                 }
             }
+            Payload::ReadOk { value } => {
+                if let Some(in_reply_to) = in_reply_to {
+                    self.total_read(in_reply_to, value.0, output)?;
+                }
+            }
+            Payload::Error { code, .. } => {
+                let Some(in_reply_to) = in_reply_to else {
+                    return Ok(());
+                };
+                if code == error_code::KEY_DOES_NOT_EXIST {
+                    // that node hasn't added anything yet
+                    self.total_read(in_reply_to, BigInt::default(), output)?;
+                } else if let Some(read) = self.syncs.remove(&in_reply_to) {
+                    self.sync(read, output)?;
+                } else if let Some((read, node)) = self.read_keys.remove(&in_reply_to) {
+                    self.read_total(read, node, output)?;
+                } else if let Some((_, acked)) =
+                    self.writing.take_if(|(msg_id, _)| *msg_id == in_reply_to)
+                {
+                    // write it again, together with whatever has been added in the meantime
+                    self.waiting.splice(0..0, acked);
+                    self.flush(output)?;
+                }
+            }
+            Payload::AddOk
+            | Payload::Gossip { .. }
+            | Payload::GossipOk { .. }
+            | Payload::Transfer { .. }
+            | Payload::TransferOk { .. }
+            | Payload::Fetch { .. }
+            | Payload::FetchOk { .. } => {}
         }
         Ok(())
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    main_loop::<_, GrowCounterNode, _, _>(())
+    let backend = match std::env::var("COUNTER_BACKEND") {
+        Ok(backend) => backend.parse()?,
+        Err(_) => Backend::Crdt,
+    };
+    match backend {
+        Backend::Crdt => main_loop::<_, GrowCounterNode, _, _>(false),
+        Backend::Bounded => main_loop::<_, GrowCounterNode, _, _>(true),
+        Backend::SeqKv => main_loop::<_, SeqKvCounterNode, _, _>(()),
+    }
 }
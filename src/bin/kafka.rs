@@ -1,155 +1,2711 @@
 use distributed::*;
 
 use anyhow::Context;
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, io::StdoutLock};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{
+        hash_map::{DefaultHasher, Entry},
+        BTreeMap, BTreeSet, HashMap, HashSet,
+    },
+    fs::{File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{BufReader, Read, Seek, SeekFrom, StdoutLock, Write},
+    path::PathBuf,
+    rc::Rc,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+// how often nodes resend log entries their peers haven't acknowledged yet
+const REPLICATE_INTERVAL: Duration = Duration::from_millis(200);
+// the most entries one replicate message carries
+const REPLICATE_BATCH: usize = 512;
+// followers that leave an entry unacknowledged for this long drop out of the in-sync replicas,
+// until they have acknowledged everything older again
+const REPLICA_LAG: Duration = Duration::from_secs(1);
+// sends forwarded to a leader that hasn't answered for this long are given up on
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+// sends whose entry not enough nodes have stored for this long get a timeout, though the entry
+// stays in the log. shorter than FORWARD_TIMEOUT, so that forwarding nodes pass it on.
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+// atomic batches whose leaders haven't all prepared their entries this long are aborted
+const PREPARE_TIMEOUT: Duration = Duration::from_secs(1);
+// leaders that prepared their part of an atomic batch this long ago and heard of no decision ask
+// the coordinator about it. longer than PREPARE_TIMEOUT, by when a coordinator has decided.
+const DECISION_TIMEOUT: Duration = Duration::from_secs(2);
+const LIN_KV: &str = "lin-kv";
+// lin-kv requests that go unanswered this long, say during a partition, are sent again
+const ALLOCATE_TIMEOUT: Duration = Duration::from_secs(1);
+// how long to back off after losing a compare-and-set race, doubling with every lost race
+const MIN_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_BACKOFF: Duration = Duration::from_millis(320);
+// how many tags a claim on a range of offsets in lin-kv picks from, to tell it apart from other
+// nodes' claims on the same range
+const CLAIM_TAGS: usize = 1 << 20;
+// the error for requests naming offsets a log doesn't have. codes from 1000 on are left to
+// applications by Maelstrom.
+const OFFSET_OUT_OF_RANGE: usize = 1000;
+// the errors for sends whose sequence number skips ahead of the producer's last one, or is too
+// old to tell whether it is a retry
+const OUT_OF_ORDER_SEQUENCE: usize = 1001;
+const DUPLICATE_SEQUENCE: usize = 1002;
+// how many of a producer's latest sequence numbers per key the leader remembers offsets for
+const PRODUCER_WINDOW: usize = 5;
+// retries of a producer's send that is still waiting for its offset in lin-kv get a timeout after
+// this long, though the send itself goes on
+const RETRY_TIMEOUT: Duration = Duration::from_secs(2);
+// the most messages a poll returns for one key, and in total
+const POLL_KEY_LIMIT: usize = 100;
+const POLL_LIMIT: usize = 1000;
+// consumers that haven't joined their group again for this long are dropped from it
+const SESSION_TIMEOUT: Duration = Duration::from_secs(5);
+// what one log entry, an offset and its msg, takes up in memory
+const ENTRY_BYTES: usize = std::mem::size_of::<(usize, usize)>();
+
+enum Mode {
+    // the leader of each key assigns its offsets
+    Leader,
+    // any node claims ranges of offsets for each key in lin-kv
+    LinKv,
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "leader" => Ok(Mode::Leader),
+            "lin-kv" => Ok(Mode::LinKv),
+            _ => anyhow::bail!("unknown kafka mode {}", s),
+        }
+    }
+}
+
+// how many nodes must have stored an entry before its send is acknowledged
+enum Acks {
+    // this many, counting the node that assigned its offset
+    Count(usize),
+    // every in-sync replica, and at least as many nodes as KAFKA_MIN_INSYNC asks for
+    All,
+}
+
+impl FromStr for Acks {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "all" => Ok(Acks::All),
+            _ => match s.parse() {
+                Ok(0) | Err(_) => anyhow::bail!("unknown kafka acks {}", s),
+                Ok(n) => Ok(Acks::Count(n)),
+            },
+        }
+    }
+}
+
+// which messages a node may drop from its logs. offsets never change, polls below what a log
+// still has get OFFSET_OUT_OF_RANGE. by default everything is kept.
+#[derive(Default)]
+struct Retention {
+    // drop the messages before each key's committed offset, the lowest of any group's
+    commited: bool,
+    max_msgs: Option<usize>,
+    max_bytes: Option<usize>,
+    // keep only the latest offset of every msg in a log
+    compact: bool,
+}
+
+impl FromStr for Retention {
+    type Err = anyhow::Error;
+
+    // a comma separated list of policies, like "committed,max-msgs=1000,compact"
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut retention = Retention::default();
+        for policy in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match policy.split_once('=') {
+                None if policy == "committed" => retention.commited = true,
+                None if policy == "compact" => retention.compact = true,
+                Some(("max-msgs", n)) => {
+                    retention.max_msgs = Some(n.parse().context("parse max-msgs")?)
+                }
+                Some(("max-bytes", n)) => {
+                    retention.max_bytes = Some(n.parse().context("parse max-bytes")?)
+                }
+                _ => anyhow::bail!("unknown kafka retention policy {}", policy),
+            }
+        }
+        Ok(retention)
+    }
+}
+
+impl Retention {
+    // the most entries a log may hold under the count and size caps
+    fn max_entries(&self) -> Option<usize> {
+        let by_size = self.max_bytes.map(|bytes| bytes / ENTRY_BYTES);
+        match (self.max_msgs, by_size) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+struct Config {
+    mode: Mode,
+    acks: Acks,
+    // the fewest in-sync nodes, counting the leader, that take sends with acks=all
+    min_insync: usize,
+    retention: Retention,
+    storage: Storage,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum Payload {
+    // sends with a producer id and sequence number are appended once however often they are
+    // retried. each producer numbers its sends to a key one after another.
     Send {
         key: String,
         msg: usize,
+        #[serde(default)]
+        producer: Option<String>,
+        #[serde(default)]
+        seq: Option<u64>,
     },
     SendOk {
         offset: usize,
     },
+    // appends many entries in one request, answered with the offset of each. an atomic batch is
+    // appended all or not at all, by a two-phase commit between the leaders of its keys, and
+    // polls only see it once they can see all of it.
+    SendBatch {
+        msgs: Vec<(String, usize)>,
+        #[serde(default)]
+        atomic: bool,
+    },
+    SendBatchOk {
+        offsets: Vec<usize>,
+    },
+    // the two-phase commit of an atomic batch, txn being the batch's id at the node coordinating
+    // it. a leader that prepares its part holds the next offsets of its keys for it, and the
+    // commit names every entry of the batch.
+    Prepare {
+        txn: usize,
+        msgs: Vec<(String, usize)>,
+    },
+    PrepareOk {
+        txn: usize,
+        offsets: Vec<usize>,
+    },
+    Commit {
+        txn: usize,
+        batch: Vec<(String, usize)>,
+    },
+    CommitOk {
+        txn: usize,
+    },
+    Abort {
+        txn: usize,
+    },
+    AbortOk {
+        txn: usize,
+    },
+    // a leader still waiting for the decision on a batch it prepared its part of. a coordinator
+    // that knows nothing of the batch, having restarted without storage, answers with an abort.
+    Inquire {
+        txn: usize,
+    },
     Poll {
         offsets: HashMap<String, usize>,
     },
     PollOk {
-        msgs: HashMap<String, Vec<(usize, usize)>>,
+        msgs: Polled,
+        // where to poll from next for keys that had more messages than fit in the response
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        next_offsets: HashMap<String, usize>,
     },
+    // without a group, commits and lists use the offsets shared by all clients
     CommitOffsets {
         offsets: HashMap<String, usize>,
+        #[serde(default)]
+        group: Option<String>,
     },
     CommitOffsetsOk,
     ListCommittedOffsets {
         keys: Vec<String>,
+        #[serde(default)]
+        group: Option<String>,
     },
     ListCommittedOffsetsOk {
         offsets: HashMap<String, usize>,
     },
-    GossipSend {
-        key: String,
-        msg: usize,
+    // makes the client a member of the group, or keeps it one, and answers with the keys among
+    // the given ones that it should consume
+    JoinGroup {
+        group: String,
+        #[serde(default)]
+        keys: Vec<String>,
     },
-    GossipCommit {
-        offsets: HashMap<String, usize>,
+    JoinGroupOk {
+        members: Vec<String>,
+        keys: Vec<String>,
     },
+    LeaveGroup {
+        group: String,
+    },
+    LeaveGroupOk,
+    // log entries (key, offset, msg) from the node that assigned their offsets, with every entry
+    // of the atomic batches among them
+    Replicate {
+        entries: Vec<(String, usize, usize)>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        batches: Vec<Vec<(String, usize)>>,
+    },
+    ReplicateOk {
+        entries: Vec<(String, usize)>,
+    },
+    // asks a node where its logs of the keys end, so a restarted node can send it what it misses
+    ListEnds {
+        keys: Vec<String>,
+    },
+    ListEndsOk {
+        ends: HashMap<String, usize>,
+    },
+    // the committed offsets and group memberships that changed since every other node last
+    // acknowledged them, sent to each node until it has. commits are by group, none for the
+    // offsets shared by all clients.
+    GossipGroups {
+        seq: u64,
+        commits: Vec<(Option<String>, HashMap<String, usize>)>,
+        groups: Groups,
+    },
+    GossipGroupsOk {
+        seq: u64,
+    },
+    ReadOk {
+        value: usize,
+    },
+    CasOk,
+    // lin-kv taking a new hint for where a key's claims end, which nobody waits for
+    WriteOk,
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+enum InjectedPayload {
+    Replicate,
+    // try to allocate offsets for the key again after backing off
+    Allocate { key: String },
+}
+
+// who is waiting for an entry to be stored
+enum Waiter {
+    // a send, answered with the entry's offset
+    Send(Message<Payload>),
+    // an entry of a batch, by the batch's id and the entry's position in it
+    Batch(usize, usize),
+    // an entry of our part of an atomic batch, by coordinator and transaction
+    Txn((String, usize)),
 }
 
-enum InjectedPayload {
-    GossipSend { key: String, msg: usize },
-    GossipCommitOffsets { offsets: HashMap<String, usize> },
+// a send_batch being answered
+struct Batch {
+    reply: Message<Payload>,
+    // of the entries, once they have them
+    offsets: Vec<Option<usize>>,
+    started: Instant,
+    // for atomic batches, which we coordinate
+    txn: Option<Txn>,
+}
+
+struct Txn {
+    msgs: Vec<(String, usize)>,
+    // the entries each leader appends, by their position in the batch
+    parts: HashMap<String, Vec<usize>>,
+    prepared: HashSet<String>,
+}
+
+// what became of an atomic batch of ours, with the leaders yet to acknowledge it and, for a
+// commit, every entry of the batch
+struct Decision {
+    commit: bool,
+    leaders: HashSet<String>,
+    batch: Vec<(String, usize)>,
+}
+
+// the entries of an atomic batch on one of the leaders of their keys
+enum Participant {
+    // waiting for the coordinator's decision, with the offsets held for them and since when.
+    // once a leader agreed to append them it can't back out, so they wait until the coordinator
+    // decides, or turns out to have forgotten the batch.
+    Prepared(Vec<(String, usize, usize)>, Instant),
+    // appended, with how many entries enough nodes haven't stored yet and the commit to answer
+    // once they have, none for our own part of a batch we coordinate
+    Committing(usize, Option<Message<Payload>>),
+    Committed,
+}
+
+// offsets committed for a group, none for the offsets shared by all clients. a node journals
+// every commit it takes, so that it still has them after a restart.
+#[derive(Debug, Serialize, Deserialize)]
+struct CommitRecord {
+    group: Option<String>,
+    offsets: HashMap<String, usize>,
+}
+
+// what a node remembers of atomic batches across restarts, in a journal next to its logs
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum TxnRecord {
+    // a batch we coordinate, before any of its leaders is asked to prepare. one that restarts
+    // undecided is aborted.
+    Started {
+        txn: usize,
+        leaders: Vec<String>,
+    },
+    Decided {
+        txn: usize,
+        commit: bool,
+        leaders: Vec<String>,
+        batch: Vec<(String, usize)>,
+    },
+    // every leader acknowledged the decision
+    Finished {
+        txn: usize,
+    },
+    // our part of a batch another node, or we, coordinate
+    Prepared {
+        coordinator: String,
+        txn: usize,
+        entries: Vec<(String, usize, usize)>,
+    },
+    Committed {
+        coordinator: String,
+        txn: usize,
+        batch: Vec<(String, usize)>,
+    },
+    Aborted {
+        coordinator: String,
+        txn: usize,
+    },
+    // the entries of an atomic batch replicated to us
+    Batch {
+        batch: Vec<(String, usize)>,
+    },
+}
+
+// offsets being taken in lin-kv for sends to a key. a node takes offsets for all sends it has
+// waiting at once, and sends arriving meanwhile wait for the next round.
+//
+// every range of offsets is claimed under its own lin-kv key, offset-<key>-<start>, which is
+// created once and never changed after: it holds where the range ends, along with a tag picked for
+// the claim. a compare-and-set that goes unanswered is sent again, and whether some copy of it
+// went through can always be read back later. a range claimed by someone else tells us where to
+// try next. offset-<key> only remembers about where the claims end, so that nodes don't walk
+// through every range to get there.
+struct Allocation {
+    // the sends being allocated for, in the order they get their offsets
+    sends: Vec<(Waiter, usize)>,
+    queued: Vec<(Waiter, usize)>,
+    // where the range we are claiming starts, once we know about where the claims end
+    start: Option<usize>,
+    // what our claim holds
+    claim: usize,
+    // whether our compare-and-set failed and we are reading the claim to see whose it is
+    checking: bool,
+    // the lin-kv request last sent and when, none while backing off
+    request: Option<(usize, Instant)>,
+    // every copy sent of that request, any of which may be answered
+    copies: HashSet<usize>,
+    lost_races: u32,
+}
+
+impl Allocation {
+    // moves on to claiming the range starting at start, which must be where some claim ends.
+    fn claim_from(&mut self, start: usize) {
+        let end = start + self.sends.len();
+        self.start = Some(start);
+        self.claim = end * CLAIM_TAGS + rand::random::<usize>() % CLAIM_TAGS;
+        self.checking = false;
+    }
+}
+
+// the entries we assigned offsets to that a peer hasn't acknowledged yet
+struct Replica {
+    // with when they were first sent
+    pending: BTreeMap<(String, usize), (usize, Instant)>,
+    in_sync: bool,
+}
+
+impl Replica {
+    // how long the oldest pending entry has waited
+    fn lag(&self) -> Duration {
+        self.pending
+            .values()
+            .map(|(_, sent)| sent.elapsed())
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+// the sends waiting for an entry, with the nodes that have stored it and when it was appended
+type Unstored = (Vec<Waiter>, HashSet<String>, Instant);
+
+// a sequence number a producer used for a key
+enum Sequenced {
+    // still waiting for its offset, with retries that arrived meanwhile and when they did
+    Allocating(Vec<(Message<Payload>, Instant)>),
+    Appended(usize),
+}
+
+// the offset a producer's send to a key got. leaders journal them, so that after a restart
+// they still answer retries with the offset the first send got.
+#[derive(Debug, Serialize, Deserialize)]
+struct ProducerRecord {
+    producer: String,
+    key: String,
+    seq: u64,
+    offset: usize,
+}
+
+struct KafkaNode {
+    node: String,
+    id: usize,
+    mode: Mode,
+    acks: Acks,
+    min_insync: usize,
+    retention: Retention,
+    node_ids: Vec<String>,
+    messages: Messages,
+    others: Vec<String>,
+    tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+    // sends forwarded to the leader of their key, by the id of the forwarded message, with the
+    // reply to the client that is waiting for the offset and when it was forwarded
+    forwarded: HashMap<usize, (Message<Payload>, Instant)>,
+    replicas: HashMap<String, Replica>,
+    // after a restart, the nodes that haven't told us where their logs end yet. what they miss
+    // is only known once they have.
+    recovering: HashSet<String>,
+    // entries not enough nodes have stored yet, whose sends wait for them to be
+    stored: BTreeMap<(String, usize), Unstored>,
+    // the latest sequence numbers of each producer and key we lead, and the journal of the
+    // offsets they got
+    producers: HashMap<(String, String), BTreeMap<u64, Sequenced>>,
+    producer_journal: Journal,
+    allocations: HashMap<String, Allocation>,
+    // batches being answered, by an id of ours
+    batches: HashMap<usize, Batch>,
+    // parts of batches handed to the leaders of their keys, by the id of the message, with the
+    // batch and the positions of the part's entries
+    parts: HashMap<usize, (usize, Vec<usize>)>,
+    // atomic batches we take part in, by coordinator and transaction, and the ones that were
+    // aborted, so that late prepares for them are turned down
+    txns: HashMap<(String, usize), Participant>,
+    aborted: HashSet<(String, usize)>,
+    // keys whose next offsets a prepared atomic batch holds, by its coordinator and transaction.
+    // sends to them are turned down until it is decided.
+    locks: HashMap<String, (String, usize)>,
+    // atomic batches of ours that were decided, by transaction
+    decisions: HashMap<usize, Decision>,
+    journal: Journal,
+    // every commit we took, from clients or other nodes
+    commit_journal: Journal,
+    // the members of each consumer group, with their latest join or leave
+    groups: Groups,
+    // the commits and memberships that changed here since every other node acknowledged them,
+    // how many changes there have been and up to which one each node acknowledged
+    changed_commits: HashMap<Option<String>, HashMap<String, usize>>,
+    changed_groups: Groups,
+    changes: u64,
+    changes_acked: HashMap<String, u64>,
+}
+
+// a member's latest join or leave. they are ordered by the wall clock, which the nodes share, so
+// every node settles on the same members whatever order it hears of them in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct Membership {
+    // milliseconds since the epoch
+    at: u64,
+    left: bool,
+}
+
+impl Membership {
+    fn now(left: bool) -> Self {
+        let at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self { at, left }
+    }
+
+    // members that haven't joined again for this long are gone, and so is any record of a leave
+    fn expired(&self, now: &Membership) -> bool {
+        now.at.saturating_sub(self.at) >= SESSION_TIMEOUT.as_millis() as u64
+    }
+}
+
+type Groups = HashMap<String, HashMap<String, Membership>>;
+
+// keeps the latest join or leave of every member. expired ones are left out, so late copies of
+// old joins don't bring members back.
+fn merge_groups(groups: &mut Groups, other: Groups) {
+    let now = Membership::now(false);
+    for (group, members) in other {
+        for (member, membership) in members {
+            if membership.expired(&now) {
+                continue;
+            }
+            let latest = groups
+                .entry(group.clone())
+                .or_default()
+                .entry(member)
+                .or_insert(membership);
+            *latest = membership.max(*latest);
+        }
+    }
+}
+
+fn expire_groups(groups: &mut Groups) {
+    let now = Membership::now(false);
+    for members in groups.values_mut() {
+        members.retain(|_, membership| !membership.expired(&now));
+    }
+    groups.retain(|_, members| !members.is_empty());
+}
+
+// the member of a group that consumes the key. every member ranks the keys by hashing them
+// together with its name, so a key only moves when its own member leaves or a member joins that
+// ranks higher.
+fn assignee<'a>(members: &'a [String], key: &str) -> Option<&'a String> {
+    members.iter().max_by_key(|member| {
+        let mut hasher = DefaultHasher::new();
+        (key, member).hash(&mut hasher);
+        hasher.finish()
+    })
+}
+
+impl KafkaNode {
+    // every key has a single leader that assigns its offsets, picked by hashing the key.
+    fn leader(&self, key: &str) -> &String {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.node_ids[hasher.finish() as usize % self.node_ids.len()]
+    }
+
+    fn send_to(
+        &self,
+        dst: &str,
+        id: Option<usize>,
+        payload: Payload,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        Message {
+            src: self.node.clone(),
+            dst: dst.to_string(),
+            body: Body {
+                id,
+                in_reply_to: None,
+                payload,
+            },
+        }
+        .send(&mut *output)
+        .with_context(|| format!("send to {}", dst))
+    }
+
+    // sends the changed commits and memberships to every node that hasn't acknowledged them.
+    fn gossip_groups(&self, output: &mut StdoutLock) -> anyhow::Result<()> {
+        for n in &self.others {
+            if self.changes_acked.get(n).copied().unwrap_or_default() >= self.changes {
+                continue;
+            }
+            let payload = Payload::GossipGroups {
+                seq: self.changes,
+                commits: self.changed_commits.clone().into_iter().collect(),
+                groups: self.changed_groups.clone(),
+            };
+            self.send_to(n, None, payload, output)?;
+        }
+        Ok(())
+    }
+
+    // records a join or leave of a client and tells the other nodes about it.
+    fn change_membership(
+        &mut self,
+        group: &str,
+        member: &str,
+        left: bool,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let changed = HashMap::from([(
+            group.to_string(),
+            HashMap::from([(member.to_string(), Membership::now(left))]),
+        )]);
+        merge_groups(&mut self.groups, changed.clone());
+        merge_groups(&mut self.changed_groups, changed);
+        self.changes += 1;
+        self.gossip_groups(output)
+    }
+
+    fn members(&self, group: &str) -> Vec<String> {
+        let now = Membership::now(false);
+        let mut members: Vec<_> = self
+            .groups
+            .get(group)
+            .map(|members| {
+                members
+                    .iter()
+                    .filter(|(_, membership)| !membership.left && !membership.expired(&now))
+                    .map(|(member, _)| member.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        members.sort();
+        members
+    }
+
+    fn replicate(
+        &self,
+        follower: &str,
+        entries: Vec<(String, usize, usize)>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        Message {
+            src: self.node.clone(),
+            dst: follower.to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload: Payload::Replicate {
+                    batches: self.messages.batches_of(&entries),
+                    entries,
+                },
+            },
+        }
+        .send(&mut *output)
+        .with_context(|| format!("replicate to {}", follower))
+    }
+
+    // sends a follower the oldest batch of entries it hasn't acknowledged.
+    fn catch_up(&self, follower: &str, output: &mut StdoutLock) -> anyhow::Result<()> {
+        let Some(replica) = self.replicas.get(follower) else {
+            return Ok(());
+        };
+        if replica.pending.is_empty() {
+            return Ok(());
+        }
+        let entries = replica
+            .pending
+            .iter()
+            .take(REPLICATE_BATCH)
+            .map(|((key, offset), (msg, _))| (key.clone(), *offset, *msg))
+            .collect();
+        self.replicate(follower, entries, output)
+    }
+
+    // hands an entry we assigned an offset to to every other node until they acknowledge it, and
+    // answers the send once enough of them have stored it.
+    fn publish(
+        &mut self,
+        key: &str,
+        offset: usize,
+        msg: usize,
+        waiter: Waiter,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let entry = (key.to_string(), offset);
+        let now = Instant::now();
+        for replica in self.replicas.values_mut() {
+            replica.pending.insert(entry.clone(), (msg, now));
+        }
+        for n in &self.others {
+            self.replicate(n, vec![(key.to_string(), offset, msg)], output)?;
+        }
+
+        let mut waiters = vec![];
+        if let Waiter::Send(Message {
+            body:
+                Body {
+                    payload:
+                        Payload::Send {
+                            producer: Some(producer),
+                            seq: Some(seq),
+                            ..
+                        },
+                    ..
+                },
+            ..
+        }) = &waiter
+        {
+            let window = (producer.clone(), key.to_string());
+            if let Some(sequenced) = self
+                .producers
+                .get_mut(&window)
+                .and_then(|window| window.get_mut(seq))
+            {
+                if let Sequenced::Allocating(retries) = sequenced {
+                    waiters.extend(retries.drain(..).map(|(retry, _)| Waiter::Send(retry)));
+                }
+                *sequenced = Sequenced::Appended(offset);
+                self.producer_journal.write(&ProducerRecord {
+                    producer: producer.clone(),
+                    key: key.to_string(),
+                    seq: *seq,
+                    offset,
+                })?;
+            }
+        }
+        waiters.push(waiter);
+
+        let stored_by = HashSet::from([self.node.clone()]);
+        self.stored
+            .insert(entry.clone(), (waiters, stored_by, Instant::now()));
+        self.answer_stored(&[entry], output)
+    }
+
+    // gives back sends whose sequence number is new to append them, and answers the rest: retries
+    // get the offset the first send got, once it is stored.
+    fn deduplicate(
+        &mut self,
+        key: &str,
+        producer: String,
+        seq: u64,
+        mut reply: Message<Payload>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<Option<Message<Payload>>> {
+        let window = self
+            .producers
+            .entry((producer, key.to_string()))
+            .or_default();
+        let last = window.last_key_value().map(|(last, _)| *last);
+        let (code, text) = match window.get_mut(&seq) {
+            Some(Sequenced::Allocating(retries)) => {
+                retries.push((reply, Instant::now()));
+                return Ok(None);
+            }
+            Some(Sequenced::Appended(offset)) => {
+                let offset = *offset;
+                reply.body.payload = Payload::SendOk { offset };
+                match self.stored.get_mut(&(key.to_string(), offset)) {
+                    Some((waiters, _, _)) => waiters.push(Waiter::Send(reply)),
+                    None => reply.send(&mut *output).context("reply to send")?,
+                }
+                return Ok(None);
+            }
+            None => match last {
+                Some(last) if seq <= last => (
+                    DUPLICATE_SEQUENCE,
+                    format!(
+                        "sequence number {} is too old to tell, the last is {}",
+                        seq, last
+                    ),
+                ),
+                Some(last) if seq > last + 1 => (
+                    OUT_OF_ORDER_SEQUENCE,
+                    format!("sequence number {} doesn't follow {}", seq, last),
+                ),
+                _ => {
+                    window.insert(seq, Sequenced::Allocating(Vec::new()));
+                    // sends still waiting for an offset stay, so their retries get it
+                    while window.len() > PRODUCER_WINDOW {
+                        let Some(entry) = window.first_entry() else {
+                            break;
+                        };
+                        if let Sequenced::Allocating(_) = entry.get() {
+                            break;
+                        }
+                        entry.remove();
+                    }
+                    return Ok(Some(reply));
+                }
+            },
+        };
+        reply.body.payload = Payload::Error { code, text };
+        reply.send(&mut *output).context("reply to send")?;
+        Ok(None)
+    }
+
+    fn enough_stored(&self, stored_by: &HashSet<String>) -> bool {
+        match self.acks {
+            Acks::Count(n) => stored_by.len() >= n,
+            Acks::All => {
+                stored_by.len() >= self.min_insync
+                    && self
+                        .replicas
+                        .iter()
+                        .filter(|(_, replica)| replica.in_sync)
+                        .all(|(n, _)| stored_by.contains(n))
+            }
+        }
+    }
+
+    // with acks=all, sends are turned down while fewer nodes than KAFKA_MIN_INSYNC are in sync,
+    // before anything is appended. they couldn't be acknowledged anyway.
+    fn check_insync(&self) -> Result<(), String> {
+        let Acks::All = self.acks else {
+            return Ok(());
+        };
+        let in_sync = 1 + self.replicas.values().filter(|r| r.in_sync).count();
+        if in_sync < self.min_insync {
+            return Err(format!(
+                "only {} nodes are in sync, {} are needed",
+                in_sync, self.min_insync
+            ));
+        }
+        Ok(())
+    }
+
+    // answers the sends of the entries that enough nodes have stored by now.
+    fn answer_stored(
+        &mut self,
+        entries: &[(String, usize)],
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        for entry in entries {
+            let Some((_, stored_by, _)) = self.stored.get(entry) else {
+                continue;
+            };
+            if self.enough_stored(stored_by) {
+                let (waiters, _, _) = self.stored.remove(entry).expect("entry is waiting");
+                let offset = entry.1;
+                for waiter in waiters {
+                    match waiter {
+                        Waiter::Send(mut reply) => {
+                            reply.body.payload = Payload::SendOk { offset };
+                            reply.send(&mut *output).context("reply to send")?;
+                        }
+                        Waiter::Batch(id, position) => {
+                            self.fill_batch(id, &[position], &[offset], output)?;
+                        }
+                        Waiter::Txn(txn) => self.part_stored(txn, Ok(()), output)?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // gives up on the sends of entries that not enough nodes stored in time.
+    fn expire_stored(&mut self, output: &mut StdoutLock) -> anyhow::Result<()> {
+        let expired: Vec<_> = self
+            .stored
+            .iter()
+            .filter(|(_, (_, _, appended))| appended.elapsed() >= ACK_TIMEOUT)
+            .map(|(entry, _)| entry.clone())
+            .collect();
+        for entry in expired {
+            let (waiters, stored_by, _) = self.stored.remove(&entry).expect("listed");
+            // the entry is in our log and may still reach enough nodes, so the outcome is unknown
+            let error = Payload::Error {
+                code: error_code::TIMEOUT,
+                text: format!(
+                    "only {} nodes stored offset {} of {} in time",
+                    stored_by.len(),
+                    entry.1,
+                    entry.0
+                ),
+            };
+            for waiter in waiters {
+                match waiter {
+                    Waiter::Send(mut reply) => {
+                        reply.body.payload = error.clone();
+                        reply.send(&mut *output).context("reply to send")?;
+                    }
+                    Waiter::Batch(id, _) => self.fail_batch(id, error.clone(), output)?,
+                    Waiter::Txn(txn) => self.part_stored(txn, Err(error.clone()), output)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // keys an atomic batch holds the next offsets of take no other entries until it is decided.
+    fn check_unlocked(&self, key: &str) -> Result<(), String> {
+        match self.locks.get(key) {
+            Some((coordinator, txn)) => Err(format!(
+                "{} is held by transaction {} of {}",
+                key, txn, coordinator
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // appends an entry to a key we assign offsets for.
+    fn append(
+        &mut self,
+        key: String,
+        msg: usize,
+        waiter: Waiter,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        if let Mode::LinKv = self.mode {
+            return self.take_offsets(key, vec![(waiter, msg)], output);
+        }
+
+        let offset = self.messages.add_msg(key.clone(), msg)?;
+        self.publish(&key, offset, msg, waiter, output)
+    }
+
+    // takes offsets in lin-kv for sends to a key, all of them in the same claim.
+    fn take_offsets(
+        &mut self,
+        key: String,
+        sends: Vec<(Waiter, usize)>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        match self.allocations.get_mut(&key) {
+            Some(allocation) => allocation.queued.extend(sends),
+            None => {
+                let allocation = Allocation {
+                    sends,
+                    queued: Vec::new(),
+                    start: None,
+                    claim: 0,
+                    checking: false,
+                    request: None,
+                    copies: HashSet::new(),
+                    lost_races: 0,
+                };
+                self.allocations.insert(key.clone(), allocation);
+                self.allocate(&key, output)?;
+            }
+        }
+        Ok(())
+    }
+
+    // records the offsets some entries of a batch got, and answers the batch once all of its
+    // entries have one.
+    fn fill_batch(
+        &mut self,
+        id: usize,
+        positions: &[usize],
+        offsets: &[usize],
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let Some(batch) = self.batches.get_mut(&id) else {
+            return Ok(());
+        };
+        for (position, offset) in positions.iter().zip(offsets) {
+            batch.offsets[*position] = Some(*offset);
+        }
+        if batch.offsets.iter().any(Option::is_none) {
+            return Ok(());
+        }
+
+        let mut batch = self.batches.remove(&id).expect("batch is being answered");
+        self.parts.retain(|_, (part_of, _)| *part_of != id);
+        let offsets = batch.offsets.into_iter().flatten().collect();
+        batch.reply.body.payload = Payload::SendBatchOk { offsets };
+        batch
+            .reply
+            .send(&mut *output)
+            .context("reply to send_batch")
+    }
+
+    // answers a batch with an error, giving up on the parts still outstanding.
+    fn fail_batch(
+        &mut self,
+        id: usize,
+        error: Payload,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let Some(mut batch) = self.batches.remove(&id) else {
+            return Ok(());
+        };
+        self.parts.retain(|_, (part_of, _)| *part_of != id);
+        batch.reply.body.payload = error;
+        batch
+            .reply
+            .send(&mut *output)
+            .context("reply to send_batch")
+    }
+
+    // hands a part of a batch of ours to a leader. errors it answers with find the batch by the
+    // message's id.
+    fn send_part(
+        &mut self,
+        dst: &str,
+        id: usize,
+        positions: Vec<usize>,
+        payload: Payload,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        self.send_to(dst, Some(self.id), payload, output)?;
+        self.parts.insert(self.id, (id, positions));
+        self.id += 1;
+        Ok(())
+    }
+
+    // checks that we can append our part of an atomic batch, and holds the next offsets of its
+    // keys for it. answers with the offsets, or why not.
+    fn prepare(
+        &mut self,
+        coordinator: &str,
+        txn: usize,
+        msgs: Vec<(String, usize)>,
+    ) -> anyhow::Result<Payload> {
+        let id = (coordinator.to_string(), txn);
+        let refuse = |code, text| Ok(Payload::Error { code, text });
+        match self.txns.get(&id) {
+            // a retry
+            Some(Participant::Prepared(entries, _)) => {
+                let offsets = entries.iter().map(|(_, offset, _)| *offset).collect();
+                return Ok(Payload::PrepareOk { txn, offsets });
+            }
+            Some(_) => {
+                let text = format!("transaction {} of {} was committed", txn, coordinator);
+                return refuse(error_code::TXN_CONFLICT, text);
+            }
+            None if self.aborted.contains(&id) => {
+                let text = format!("transaction {} of {} was aborted", txn, coordinator);
+                return refuse(error_code::ABORT, text);
+            }
+            None => {}
+        }
+        for (key, _) in &msgs {
+            if self.leader(key) != &self.node {
+                let text = format!("{} doesn't lead {}", self.node, key);
+                return refuse(error_code::PRECONDITION_FAILED, text);
+            }
+            if self.locks.contains_key(key) {
+                let text = format!("{} is held by another atomic batch", key);
+                return refuse(error_code::TXN_CONFLICT, text);
+            }
+        }
+        if let Err(text) = self.check_insync() {
+            return refuse(error_code::TEMPORARILY_UNAVAILABLE, text);
+        }
+
+        let mut next = HashMap::new();
+        let mut entries = Vec::new();
+        for (key, msg) in msgs {
+            let offset = *next
+                .entry(key.clone())
+                .or_insert_with(|| self.messages.end(&key));
+            next.insert(key.clone(), offset + 1);
+            entries.push((key, offset, msg));
+        }
+        self.journal.write(&TxnRecord::Prepared {
+            coordinator: coordinator.to_string(),
+            txn,
+            entries: entries.clone(),
+        })?;
+        for (key, _, _) in &entries {
+            self.locks.insert(key.clone(), id.clone());
+        }
+        let offsets = entries.iter().map(|(_, offset, _)| *offset).collect();
+        self.txns
+            .insert(id, Participant::Prepared(entries, Instant::now()));
+        Ok(Payload::PrepareOk { txn, offsets })
+    }
+
+    // a leader prepared its part of an atomic batch of ours. once every one has, it commits.
+    fn prepared(
+        &mut self,
+        txn: usize,
+        leader: &str,
+        offsets: Vec<usize>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        if self.decisions.contains_key(&txn) {
+            return Ok(());
+        }
+        let Some(Batch {
+            txn: Some(t),
+            offsets: batch_offsets,
+            ..
+        }) = self.batches.get_mut(&txn)
+        else {
+            return Ok(());
+        };
+        let Some(positions) = t.parts.get(leader) else {
+            return Ok(());
+        };
+        for (position, offset) in positions.iter().zip(offsets) {
+            batch_offsets[*position] = Some(offset);
+        }
+        t.prepared.insert(leader.to_string());
+        if t.parts.keys().all(|n| t.prepared.contains(n)) {
+            self.decide(txn, Ok(()), output)?;
+        }
+        Ok(())
+    }
+
+    // settles an atomic batch of ours: commits it, or aborts it and answers with the error. the
+    // decision is journaled before any leader hears of it, and told to them until they have all
+    // acknowledged it.
+    fn decide(
+        &mut self,
+        txn: usize,
+        outcome: Result<(), Payload>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        if self.decisions.contains_key(&txn) {
+            return Ok(());
+        }
+        let Some(Batch {
+            txn: Some(t),
+            offsets,
+            ..
+        }) = self.batches.get(&txn)
+        else {
+            return Ok(());
+        };
+        let commit = outcome.is_ok();
+        let leaders: Vec<_> = t.parts.keys().cloned().collect();
+        let batch = match commit {
+            true => t
+                .msgs
+                .iter()
+                .zip(offsets)
+                .map(|((key, _), offset)| (key.clone(), offset.expect("every part is prepared")))
+                .collect(),
+            false => Vec::new(),
+        };
+        self.journal.write(&TxnRecord::Decided {
+            txn,
+            commit,
+            leaders: leaders.clone(),
+            batch: batch.clone(),
+        })?;
+        // late answers to the prepares don't matter anymore
+        self.parts.retain(|_, (part_of, _)| *part_of != txn);
+        if let Err(error) = outcome {
+            // nobody appended anything, so the batch definitely failed
+            self.fail_batch(txn, error, output)?;
+        }
+        let decision = Decision {
+            commit,
+            leaders: leaders.into_iter().collect(),
+            batch,
+        };
+        self.decisions.insert(txn, decision);
+        self.drive(txn, output)
+    }
+
+    // carries out a decision of ours on our own part, and tells the other leaders yet to
+    // acknowledge it.
+    fn drive(&mut self, txn: usize, output: &mut StdoutLock) -> anyhow::Result<()> {
+        let Some(decision) = self.decisions.get(&txn) else {
+            return Ok(());
+        };
+        if decision.leaders.contains(&self.node) {
+            let id = (self.node.clone(), txn);
+            match (decision.commit, self.txns.get(&id)) {
+                (true, Some(Participant::Prepared(..))) => {
+                    let batch = decision.batch.clone();
+                    self.commit_part(id, batch, None, output)?;
+                }
+                // answered once the entries are stored
+                (true, Some(Participant::Committing(..))) => {}
+                (true, Some(Participant::Committed)) => {
+                    self.leader_done(txn, &id.0, Ok(()), output)?;
+                }
+                (true, None) => {
+                    let error = Payload::Error {
+                        code: error_code::CRASH,
+                        text: format!("{} lost its part of the batch", self.node),
+                    };
+                    self.leader_done(txn, &id.0, Err(error), output)?;
+                }
+                (false, _) => {
+                    self.abort_part(&id)?;
+                    self.leader_done(txn, &id.0, Ok(()), output)?;
+                }
+            }
+        }
+
+        let Some(decision) = self.decisions.get(&txn) else {
+            return Ok(());
+        };
+        let requests: Vec<_> = decision
+            .leaders
+            .iter()
+            .filter(|n| *n != &self.node)
+            .map(|n| {
+                let payload = match decision.commit {
+                    true => Payload::Commit {
+                        txn,
+                        batch: decision.batch.clone(),
+                    },
+                    false => Payload::Abort { txn },
+                };
+                (n.clone(), payload)
+            })
+            .collect();
+        for (n, payload) in requests {
+            self.send_part(&n, txn, Vec::new(), payload, output)?;
+        }
+        Ok(())
+    }
+
+    // a leader carried out our decision on an atomic batch, or couldn't. the batch is answered
+    // once every leader stored its part of a commit.
+    fn leader_done(
+        &mut self,
+        txn: usize,
+        leader: &str,
+        result: Result<(), Payload>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let Some(decision) = self.decisions.get_mut(&txn) else {
+            return Ok(());
+        };
+        if !decision.leaders.remove(leader) {
+            return Ok(());
+        }
+        let finished = decision.leaders.is_empty();
+        if let Err(error) = result {
+            self.fail_batch(txn, error, output)?;
+        }
+        if !finished {
+            return Ok(());
+        }
+
+        let decision = self.decisions.remove(&txn).expect("decision exists");
+        self.journal.write(&TxnRecord::Finished { txn })?;
+        self.parts.retain(|_, (part_of, _)| *part_of != txn);
+        if let (true, Some(mut batch)) = (decision.commit, self.batches.remove(&txn)) {
+            let offsets = batch.offsets.into_iter().flatten().collect();
+            batch.reply.body.payload = Payload::SendBatchOk { offsets };
+            batch
+                .reply
+                .send(&mut *output)
+                .context("reply to send_batch")?;
+        }
+        Ok(())
+    }
+
+    // appends our prepared part of a committed atomic batch at the offsets held for it. polls
+    // don't see it until they can see the whole batch.
+    fn commit_part(
+        &mut self,
+        id: (String, usize),
+        batch: Vec<(String, usize)>,
+        reply: Option<Message<Payload>>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let Some(Participant::Prepared(entries, _)) = self.txns.remove(&id) else {
+            unreachable!("only prepared parts are committed");
+        };
+        self.journal.write(&TxnRecord::Committed {
+            coordinator: id.0.clone(),
+            txn: id.1,
+            batch: batch.clone(),
+        })?;
+        self.messages.add_batch(batch);
+        let committing = Participant::Committing(entries.len(), reply);
+        self.txns.insert(id.clone(), committing);
+        self.locks.retain(|_, holder| *holder != id);
+        for (key, offset, msg) in entries {
+            self.messages.insert_msg(key.clone(), offset, msg)?;
+            self.publish(&key, offset, msg, Waiter::Txn(id.clone()), output)?;
+        }
+        Ok(())
+    }
+
+    // drops our prepared part of an aborted atomic batch, and remembers the abort so that a late
+    // prepare doesn't hold its keys again.
+    fn abort_part(&mut self, id: &(String, usize)) -> anyhow::Result<Payload> {
+        match self.txns.get(id) {
+            Some(Participant::Prepared(..)) => {
+                self.txns.remove(id);
+                self.locks.retain(|_, holder| holder != id);
+            }
+            Some(_) => {
+                return Ok(Payload::Error {
+                    code: error_code::TXN_CONFLICT,
+                    text: format!("transaction {} of {} was committed", id.1, id.0),
+                })
+            }
+            None => {}
+        }
+        if self.aborted.insert(id.clone()) {
+            self.journal.write(&TxnRecord::Aborted {
+                coordinator: id.0.clone(),
+                txn: id.1,
+            })?;
+        }
+        Ok(Payload::AbortOk { txn: id.1 })
+    }
+
+    // an entry of our part of an atomic batch was stored by enough nodes, or wasn't in time. the
+    // coordinator hears once all of them are, or of the first that wasn't.
+    fn part_stored(
+        &mut self,
+        id: (String, usize),
+        result: Result<(), Payload>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let Some(Participant::Committing(unstored, reply)) = self.txns.get_mut(&id) else {
+            return Ok(());
+        };
+        *unstored -= 1;
+        if *unstored > 0 && result.is_ok() {
+            return Ok(());
+        }
+        let reply = reply.take();
+        self.txns.insert(id.clone(), Participant::Committed);
+        if id.0 == self.node {
+            return self.leader_done(id.1, &id.0, result, output);
+        }
+        if let Some(mut reply) = reply {
+            reply.body.payload = match result {
+                Ok(()) => Payload::CommitOk { txn: id.1 },
+                Err(error) => error,
+            };
+            reply.send(&mut *output).context("reply to commit")?;
+        }
+        Ok(())
+    }
+
+    // drives the batches we coordinate along: retries what went unanswered, aborts atomic batches
+    // that couldn't prepare and gives up on others whose leaders don't answer.
+    fn check_batches(&mut self, output: &mut StdoutLock) -> anyhow::Result<()> {
+        let mut requests = Vec::new();
+        let mut aborted = Vec::new();
+        let mut abandoned = Vec::new();
+        for (id, batch) in &self.batches {
+            let elapsed = batch.started.elapsed();
+            match &batch.txn {
+                // the decision is driven below
+                Some(_) if self.decisions.contains_key(id) => {}
+                Some(_) if elapsed >= PREPARE_TIMEOUT => aborted.push(*id),
+                Some(txn) => {
+                    for (n, positions) in &txn.parts {
+                        if !txn.prepared.contains(n) {
+                            let msgs = positions.iter().map(|p| txn.msgs[*p].clone()).collect();
+                            let payload = Payload::Prepare { txn: *id, msgs };
+                            requests.push((n.clone(), *id, positions.clone(), payload));
+                        }
+                    }
+                }
+                None if elapsed >= FORWARD_TIMEOUT => abandoned.push(*id),
+                None => {}
+            }
+        }
+        for (n, id, positions, payload) in requests {
+            self.send_part(&n, id, positions, payload, output)?;
+        }
+
+        for id in aborted {
+            let Some(Batch { txn: Some(txn), .. }) = self.batches.get(&id) else {
+                continue;
+            };
+            let unprepared: Vec<_> = txn
+                .parts
+                .keys()
+                .filter(|n| !txn.prepared.contains(*n))
+                .cloned()
+                .collect();
+            let error = Payload::Error {
+                code: error_code::ABORT,
+                text: format!("{} didn't prepare in time", unprepared.join(", ")),
+            };
+            self.decide(id, Err(error), output)?;
+        }
+        let decided: Vec<_> = self.decisions.keys().copied().collect();
+        for txn in decided {
+            self.drive(txn, output)?;
+        }
+
+        for id in abandoned {
+            // the leaders may still have appended some of it, so the outcome is unknown
+            let error = Payload::Error {
+                code: error_code::TIMEOUT,
+                text: "the leaders of the batch's keys didn't answer".to_string(),
+            };
+            self.fail_batch(id, error, output)?;
+        }
+        Ok(())
+    }
+
+    // asks the coordinators of the parts we prepared long ago what became of their batches.
+    fn inquire(&self, output: &mut StdoutLock) -> anyhow::Result<()> {
+        for ((coordinator, txn), participant) in &self.txns {
+            let Participant::Prepared(_, since) = participant else {
+                continue;
+            };
+            if coordinator == &self.node || since.elapsed() < DECISION_TIMEOUT {
+                continue;
+            }
+            let payload = Payload::Inquire { txn: *txn };
+            self.send_to(coordinator, None, payload, output)?;
+        }
+        Ok(())
+    }
+
+    // picks up the atomic batches the journal had in flight before a restart. prepared parts
+    // hold their keys again, and batches of ours that weren't decided yet are aborted.
+    fn recover(&mut self, records: Vec<TxnRecord>) -> anyhow::Result<()> {
+        let mut undecided = HashMap::new();
+        for record in records {
+            match record {
+                TxnRecord::Started { txn, leaders } => {
+                    // transactions are named after batch ids, which mustn't come round again
+                    self.id = self.id.max(txn + 1);
+                    undecided.insert(txn, leaders);
+                }
+                TxnRecord::Decided {
+                    txn,
+                    commit,
+                    leaders,
+                    batch,
+                } => {
+                    undecided.remove(&txn);
+                    let leaders = leaders.into_iter().collect();
+                    let decision = Decision {
+                        commit,
+                        leaders,
+                        batch,
+                    };
+                    self.decisions.insert(txn, decision);
+                }
+                TxnRecord::Finished { txn } => {
+                    self.decisions.remove(&txn);
+                }
+                TxnRecord::Prepared {
+                    coordinator,
+                    txn,
+                    entries,
+                } => {
+                    let id = (coordinator, txn);
+                    for (key, _, _) in &entries {
+                        self.locks.insert(key.clone(), id.clone());
+                    }
+                    // the wait for a decision starts over
+                    self.txns
+                        .insert(id, Participant::Prepared(entries, Instant::now()));
+                }
+                TxnRecord::Committed {
+                    coordinator,
+                    txn,
+                    batch,
+                } => {
+                    let id = (coordinator, txn);
+                    self.messages.add_batch(batch);
+                    self.locks.retain(|_, holder| *holder != id);
+                    // entries a crash kept from being appended are appended now
+                    if let Some(Participant::Prepared(entries, _)) = self.txns.remove(&id) {
+                        for (key, offset, msg) in entries {
+                            self.messages.insert_msg(key, offset, msg)?;
+                        }
+                    }
+                    self.txns.insert(id, Participant::Committed);
+                }
+                TxnRecord::Aborted { coordinator, txn } => {
+                    let id = (coordinator, txn);
+                    self.locks.retain(|_, holder| *holder != id);
+                    self.txns.remove(&id);
+                    self.aborted.insert(id);
+                }
+                TxnRecord::Batch { batch } => self.messages.add_batch(batch),
+            }
+        }
+        for (txn, leaders) in undecided {
+            let record = TxnRecord::Decided {
+                txn,
+                commit: false,
+                leaders: leaders.clone(),
+                batch: Vec::new(),
+            };
+            self.journal.write(&record)?;
+            let decision = Decision {
+                commit: false,
+                leaders: leaders.into_iter().collect(),
+                batch: Vec::new(),
+            };
+            self.decisions.insert(txn, decision);
+        }
+        Ok(())
+    }
+
+    // rebuilds the producer windows from the journal, which then only keeps what they hold.
+    fn recover_producers(&mut self, records: Vec<ProducerRecord>) -> anyhow::Result<()> {
+        for record in records {
+            let window = self
+                .producers
+                .entry((record.producer, record.key))
+                .or_default();
+            window.insert(record.seq, Sequenced::Appended(record.offset));
+            while window.len() > PRODUCER_WINDOW {
+                window.pop_first();
+            }
+        }
+        let mut kept = Vec::new();
+        for ((producer, key), window) in &self.producers {
+            for (seq, sequenced) in window {
+                let Sequenced::Appended(offset) = sequenced else {
+                    continue;
+                };
+                kept.push(ProducerRecord {
+                    producer: producer.clone(),
+                    key: key.clone(),
+                    seq: *seq,
+                    offset: *offset,
+                });
+            }
+        }
+        self.producer_journal.rewrite(&kept)
+    }
+
+    // sends the next request for the key's allocation: reading about where the claims end,
+    // claiming the range from there, or reading that claim back once claiming it failed.
+    fn allocate(&mut self, key: &str, output: &mut StdoutLock) -> anyhow::Result<()> {
+        let allocation = &self.allocations[key];
+        let request = match allocation.start {
+            None => KvRequest::Read {
+                key: format!("offset-{}", key),
+            },
+            Some(start) if allocation.checking => KvRequest::Read {
+                key: format!("offset-{}-{}", key, start),
+            },
+            Some(start) => KvRequest::Cas {
+                key: format!("offset-{}-{}", key, start),
+                // no claim holds 0, so this only succeeds in creating the claim
+                from: 0,
+                to: allocation.claim,
+                create_if_not_exists: true,
+            },
+        };
+        self.send_kv(request, output)?;
+        let allocation = self.allocations.get_mut(key).expect("allocation exists");
+        allocation.copies.insert(self.id);
+        allocation.request = Some((self.id, Instant::now()));
+        self.id += 1;
+        Ok(())
+    }
+
+    fn send_kv(
+        &mut self,
+        request: KvRequest<usize>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        Message {
+            src: self.node.clone(),
+            dst: LIN_KV.to_string(),
+            body: Body {
+                id: Some(self.id),
+                in_reply_to: None,
+                payload: request,
+            },
+        }
+        .send(&mut *output)
+        .context("send request to lin-kv")
+    }
+
+    // handles lin-kv's answer for the allocation that sent the request it replies to.
+    fn allocated(
+        &mut self,
+        in_reply_to: Option<usize>,
+        payload: Payload,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let Some(in_reply_to) = in_reply_to else {
+            return Ok(());
+        };
+        // answers to requests an allocation has moved on from are ignored
+        let Some(key) = self
+            .allocations
+            .iter()
+            .find(|(_, a)| a.copies.contains(&in_reply_to))
+            .map(|(key, _)| key.clone())
+        else {
+            return Ok(());
+        };
+        let allocation = self.allocations.get_mut(&key).expect("allocation exists");
+        allocation.copies.clear();
+        let succeeded = match (allocation.start, allocation.checking, payload) {
+            (None, _, Payload::ReadOk { value }) => {
+                allocation.claim_from(value);
+                false
+            }
+            (None, _, Payload::Error { code, .. }) if code == error_code::KEY_DOES_NOT_EXIST => {
+                allocation.claim_from(0);
+                false
+            }
+            (Some(_), false, Payload::CasOk) => true,
+            (Some(_), false, Payload::Error { code, .. })
+                if code == error_code::PRECONDITION_FAILED =>
+            {
+                allocation.checking = true;
+                false
+            }
+            (Some(_), true, Payload::ReadOk { value }) if value == allocation.claim => true,
+            // someone else claimed the range first: ours starts where theirs ends
+            (Some(_), true, Payload::ReadOk { value }) => {
+                allocation.claim_from(value / CLAIM_TAGS);
+                false
+            }
+            // lin-kv failed: back off and send the same request again
+            _ => {
+                allocation.request = None;
+                let backoff = MIN_BACKOFF * 2u32.pow(allocation.lost_races.min(6));
+                allocation.lost_races += 1;
+                let backoff = backoff
+                    .min(MAX_BACKOFF)
+                    .mul_f64(rand::random::<f64>() + 0.5);
+                let tx = self.tx.clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(backoff);
+                    let _ = tx.send(Event::Injected(InjectedPayload::Allocate { key }));
+                });
+                return Ok(());
+            }
+        };
+        if succeeded {
+            let start = allocation.start.expect("claimed from a known start");
+            let end = start + allocation.sends.len();
+            let sends = std::mem::take(&mut allocation.sends);
+            allocation.sends = std::mem::take(&mut allocation.queued);
+            allocation.lost_races = 0;
+            if allocation.sends.is_empty() {
+                self.allocations.remove(&key);
+            } else {
+                allocation.claim_from(end);
+            }
+            // nobody waits for this: a stale hint only means walking through a few more claims
+            let hint = KvRequest::Write {
+                key: format!("offset-{}", key),
+                value: end,
+            };
+            self.send_kv(hint, output)?;
+            self.id += 1;
+            // atomic batches that got their offsets here, which polls see all at once
+            let mut atomic: HashMap<usize, Vec<(String, usize)>> = HashMap::new();
+            for (offset, (waiter, _)) in (start..).zip(&sends) {
+                let Waiter::Batch(id, _) = waiter else {
+                    continue;
+                };
+                let is_atomic = self.batches.get(id).is_some_and(|batch| {
+                    matches!(
+                        batch.reply.body.payload,
+                        Payload::SendBatch { atomic: true, .. }
+                    )
+                });
+                if is_atomic {
+                    atomic.entry(*id).or_default().push((key.clone(), offset));
+                }
+            }
+            for batch in atomic.into_values() {
+                self.journal.write(&TxnRecord::Batch {
+                    batch: batch.clone(),
+                })?;
+                self.messages.add_batch(batch);
+            }
+            for (offset, (waiter, msg)) in (start..).zip(sends) {
+                self.messages.insert_msg(key.clone(), offset, msg)?;
+                self.publish(&key, offset, msg, waiter, output)?;
+            }
+            if !self.allocations.contains_key(&key) {
+                return Ok(());
+            }
+        }
+        self.allocate(&key, output)
+    }
+}
+
+// a key's messages
+// where a node keeps its logs
+enum Storage {
+    Memory,
+    // append-only segment files, a directory of them for every key
+    Segments { dir: PathBuf, fsync: Fsync },
+}
+
+impl Storage {
+    fn open(&self, key: &str) -> anyhow::Result<Box<dyn Store>> {
+        match self {
+            Storage::Memory => Ok(Box::<MemoryStore>::default()),
+            Storage::Segments { dir, fsync } => {
+                let store = SegmentStore::open(dir.join(key_dir(key)), *fsync)
+                    .with_context(|| format!("open the log of {}", key))?;
+                Ok(Box::new(store))
+            }
+        }
+    }
+}
+
+// keys can hold any character, so their directories are named after their bytes in hex
+fn key_dir(key: &str) -> String {
+    let hex: String = key.bytes().map(|b| format!("{:02x}", b)).collect();
+    format!("key-{}", hex)
+}
+
+fn dir_key(name: &str) -> Option<String> {
+    let hex = name.strip_prefix("key-")?;
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+#[derive(Clone, Copy)]
+enum Fsync {
+    // before anything written is acknowledged
+    Always,
+    // on every replicate tick
+    Interval,
+    // whenever the OS gets to it
+    Never,
+}
+
+impl FromStr for Fsync {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "always" => Ok(Fsync::Always),
+            "interval" => Ok(Fsync::Interval),
+            "never" => Ok(Fsync::Never),
+            _ => anyhow::bail!("unknown kafka fsync policy {}", s),
+        }
+    }
+}
+
+// records a node keeps next to its logs, one JSON object per line. memory storage keeps none, a
+// restarted node has lost its logs as well then.
+struct Journal {
+    file: Option<(PathBuf, File)>,
+    fsync: Fsync,
+    // written to but not synced yet
+    dirty: bool,
+}
+
+impl Journal {
+    // opens the journal of that name in the node's storage, with the records it already has.
+    fn open<R: DeserializeOwned>(storage: &Storage, name: &str) -> anyhow::Result<(Self, Vec<R>)> {
+        let Storage::Segments { dir, fsync } = storage else {
+            let journal = Self {
+                file: None,
+                fsync: Fsync::Never,
+                dirty: false,
+            };
+            return Ok((journal, Vec::new()));
+        };
+        let path = dir.join(format!("{}.jsonl", name));
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).context("read journal"),
+        };
+        let mut records = Vec::new();
+        let mut valid = 0;
+        for line in text.split_inclusive('\n') {
+            // a write torn by a crash, nothing after it was acknowledged
+            let Some(Ok(record)) = line.strip_suffix('\n').map(serde_json::from_str) else {
+                break;
+            };
+            records.push(record);
+            valid += line.len();
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context("open journal")?;
+        if valid < text.len() {
+            file.set_len(valid as u64)
+                .context("cut torn write off journal")?;
+        }
+        let journal = Self {
+            file: Some((path, file)),
+            fsync: *fsync,
+            dirty: false,
+        };
+        Ok((journal, records))
+    }
+
+    // replaces every record with the given ones, which say the same in fewer lines.
+    fn rewrite<R: Serialize>(&mut self, records: &[R]) -> anyhow::Result<()> {
+        let Some((path, _)) = &self.file else {
+            return Ok(());
+        };
+        let mut text = String::new();
+        for record in records {
+            text += &serde_json::to_string(record).context("serialize journal record")?;
+            text.push('\n');
+        }
+        // the old journal stays whole until the new one takes its place
+        let new = path.with_extension("jsonl.new");
+        std::fs::write(&new, text).context("write journal")?;
+        File::open(&new)
+            .and_then(|file| file.sync_all())
+            .context("sync journal")?;
+        std::fs::rename(&new, path).context("replace journal")?;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .context("open journal")?;
+        self.file = Some((path.clone(), file));
+        Ok(())
+    }
+
+    fn write(&mut self, record: &impl Serialize) -> anyhow::Result<()> {
+        let Some((_, file)) = &mut self.file else {
+            return Ok(());
+        };
+        let mut line = serde_json::to_string(record).context("serialize journal record")?;
+        line.push('\n');
+        file.write_all(line.as_bytes())
+            .context("write to journal")?;
+        match self.fsync {
+            Fsync::Always => file.sync_data().context("sync journal")?,
+            Fsync::Interval => self.dirty = true,
+            Fsync::Never => {}
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        if let (true, Some((_, file))) = (self.dirty, &self.file) {
+            file.sync_data().context("sync journal")?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+// the entries of one log below its end. they are appended in offset order and only ever removed
+// by retention.
+trait Store {
+    // the first offset still kept, and the one the next append gets
+    fn start(&self) -> usize;
+    fn end(&self) -> usize;
+    fn len(&self) -> usize;
+    fn append(&mut self, offset: usize, msg: usize) -> anyhow::Result<()>;
+    // at most limit entries, from the offset on
+    fn read(&self, from: usize, limit: usize) -> anyhow::Result<Vec<(usize, usize)>>;
+    fn remove(&mut self, offset: usize) -> anyhow::Result<()>;
+    // drops every entry below the offset
+    fn truncate(&mut self, offset: usize) -> anyhow::Result<()>;
+    fn sync(&mut self) -> anyhow::Result<()>;
+}
+
+#[derive(Default)]
+struct MemoryStore {
+    msgs: BTreeMap<usize, usize>,
+    start: usize,
+    end: usize,
+}
+
+impl Store for MemoryStore {
+    fn start(&self) -> usize {
+        self.start
+    }
+
+    fn end(&self) -> usize {
+        self.end
+    }
+
+    fn len(&self) -> usize {
+        self.msgs.len()
+    }
+
+    fn append(&mut self, offset: usize, msg: usize) -> anyhow::Result<()> {
+        self.msgs.insert(offset, msg);
+        self.end = offset + 1;
+        Ok(())
+    }
+
+    fn read(&self, from: usize, limit: usize) -> anyhow::Result<Vec<(usize, usize)>> {
+        Ok(self
+            .msgs
+            .range(from..)
+            .take(limit)
+            .map(|(o, m)| (*o, *m))
+            .collect())
+    }
+
+    fn remove(&mut self, offset: usize) -> anyhow::Result<()> {
+        self.msgs.remove(&offset);
+        Ok(())
+    }
+
+    fn truncate(&mut self, offset: usize) -> anyhow::Result<()> {
+        self.start = offset;
+        self.msgs = self.msgs.split_off(&offset);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+// a record is its kind followed by an offset and a msg, both little endian
+const RECORD_BYTES: usize = 17;
+const APPEND: u8 = 0;
+// the entry at the offset was compacted away
+const REMOVE: u8 = 1;
+// the entries below the offset were dropped
+const TRUNCATE: u8 = 2;
+// a new segment is started once the last one has this many records
+const SEGMENT_RECORDS: usize = 4096;
+// a segment's index has the position of every this many entries appended to it
+const INDEX_INTERVAL: usize = 64;
+
+fn encode_record(kind: u8, offset: usize, msg: usize) -> [u8; RECORD_BYTES] {
+    let mut record = [0; RECORD_BYTES];
+    record[0] = kind;
+    record[1..9].copy_from_slice(&(offset as u64).to_le_bytes());
+    record[9..].copy_from_slice(&(msg as u64).to_le_bytes());
+    record
+}
+
+fn decode_record(record: &[u8]) -> (u8, usize, usize) {
+    let offset = u64::from_le_bytes(record[1..9].try_into().expect("records are 17 bytes"));
+    let msg = u64::from_le_bytes(record[9..].try_into().expect("records are 17 bytes"));
+    (record[0], offset as usize, msg as usize)
+}
+
+struct Segment {
+    path: PathBuf,
+    records: usize,
+    appended: usize,
+    // (offset, byte position) of every INDEX_INTERVAL-th entry, to start reads close to the
+    // offset they ask for
+    index: Vec<(usize, u64)>,
+}
+
+impl Segment {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            records: 0,
+            appended: 0,
+            index: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, kind: u8, offset: usize) {
+        if kind == APPEND {
+            if self.appended.is_multiple_of(INDEX_INTERVAL) {
+                let position = (self.records * RECORD_BYTES) as u64;
+                self.index.push((offset, position));
+            }
+            self.appended += 1;
+        }
+        self.records += 1;
+    }
+}
+
+// a log in segment files named after the first offset appended to them. files are only ever
+// appended to, so removals and truncations are records as well, and a segment is deleted once
+// truncation passes all of its entries. the indexes are rebuilt from the records on startup.
+struct SegmentStore {
+    dir: PathBuf,
+    fsync: Fsync,
+    // by the offset they start at
+    segments: BTreeMap<usize, Segment>,
+    // the last segment, open for appending
+    active: Option<File>,
+    // written to but not synced yet
+    dirty: bool,
+    // compacted offsets, which reads skip
+    removed: BTreeSet<usize>,
+    start: usize,
+    end: usize,
+}
+
+impl SegmentStore {
+    fn open(dir: PathBuf, fsync: Fsync) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir).context("create log directory")?;
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(&dir).context("list segments")? {
+            let path = entry.context("list segments")?.path();
+            if path.extension().is_some_and(|e| e == "log") {
+                paths.push(path);
+            }
+        }
+        // zero padded names sort by offset
+        paths.sort();
+
+        let mut store = Self {
+            dir,
+            fsync,
+            segments: BTreeMap::new(),
+            active: None,
+            dirty: false,
+            removed: BTreeSet::new(),
+            start: 0,
+            end: 0,
+        };
+        for (i, path) in paths.into_iter().enumerate() {
+            let base = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse().ok())
+                .with_context(|| {
+                    format!("segment {} isn't named after an offset", path.display())
+                })?;
+            if i == 0 {
+                store.start = base;
+                store.end = base;
+            } else if base != store.end {
+                anyhow::bail!(
+                    "segment {} doesn't follow offset {}",
+                    path.display(),
+                    store.end
+                );
+            }
+            store.recover(base, path)?;
+        }
+        store.removed = store.removed.split_off(&store.start);
+        if let Some(segment) = store.segments.values().next_back() {
+            let file = OpenOptions::new()
+                .append(true)
+                .open(&segment.path)
+                .context("open last segment")?;
+            store.active = Some(file);
+        }
+        Ok(store)
+    }
+
+    fn recover(&mut self, base: usize, path: PathBuf) -> anyhow::Result<()> {
+        let bytes = std::fs::read(&path).context("read segment")?;
+        let mut segment = Segment::new(path);
+        for record in bytes.chunks_exact(RECORD_BYTES) {
+            let (kind, offset, _) = decode_record(record);
+            match kind {
+                APPEND if offset == self.end => self.end += 1,
+                REMOVE => {
+                    self.removed.insert(offset);
+                }
+                TRUNCATE => self.start = self.start.max(offset),
+                // a write torn by a crash, nothing after it was acknowledged
+                _ => break,
+            }
+            segment.record(kind, offset);
+        }
+        let valid = segment.records * RECORD_BYTES;
+        if valid < bytes.len() {
+            OpenOptions::new()
+                .write(true)
+                .open(&segment.path)
+                .and_then(|file| file.set_len(valid as u64))
+                .context("cut torn write off segment")?;
+        }
+        self.segments.insert(base, segment);
+        Ok(())
+    }
+
+    fn write(&mut self, kind: u8, offset: usize, msg: usize) -> anyhow::Result<()> {
+        let full = self
+            .segments
+            .values()
+            .next_back()
+            .is_none_or(|s| s.records >= SEGMENT_RECORDS);
+        // only appends start segments, so every segment starts with the entry it's named after
+        if kind == APPEND && full {
+            let path = self.dir.join(format!("{:020}.log", offset));
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .context("create segment")?;
+            if let Fsync::Always = self.fsync {
+                File::open(&self.dir)
+                    .and_then(|dir| dir.sync_all())
+                    .context("sync log directory")?;
+            }
+            self.segments.insert(offset, Segment::new(path));
+            self.active = Some(file);
+        }
+
+        let file = self.active.as_mut().expect("entries are appended first");
+        file.write_all(&encode_record(kind, offset, msg))
+            .context("write to segment")?;
+        match self.fsync {
+            Fsync::Always => file.sync_data().context("sync segment")?,
+            Fsync::Interval => self.dirty = true,
+            Fsync::Never => {}
+        }
+        self.segments
+            .values_mut()
+            .next_back()
+            .expect("the active file is the last segment")
+            .record(kind, offset);
+        Ok(())
+    }
 }
 
-#[allow(dead_code)]
-struct KafkaNode {
-    node: String,
-    id: usize,
-    messages: Messages,
-    others: Vec<String>,
-    tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+impl Store for SegmentStore {
+    fn start(&self) -> usize {
+        self.start
+    }
+
+    fn end(&self) -> usize {
+        self.end
+    }
+
+    fn len(&self) -> usize {
+        // every offset from the start to the end has an entry, unless it was removed
+        self.end - self.start - self.removed.len()
+    }
+
+    fn append(&mut self, offset: usize, msg: usize) -> anyhow::Result<()> {
+        self.write(APPEND, offset, msg)?;
+        self.end = offset + 1;
+        Ok(())
+    }
+
+    fn read(&self, from: usize, limit: usize) -> anyhow::Result<Vec<(usize, usize)>> {
+        let from = from.max(self.start);
+        let mut entries = Vec::new();
+        let first = self
+            .segments
+            .range(..=from)
+            .next_back()
+            .map_or(from, |(base, _)| *base);
+        for segment in self.segments.range(first..).map(|(_, s)| s) {
+            if entries.len() >= limit {
+                break;
+            }
+            let indexed = segment.index.partition_point(|(offset, _)| *offset <= from);
+            let position = indexed.checked_sub(1).map_or(0, |i| segment.index[i].1);
+            let mut file = File::open(&segment.path).context("open segment")?;
+            file.seek(SeekFrom::Start(position))
+                .context("seek in segment")?;
+            let mut reader = BufReader::new(file);
+            let mut record = [0; RECORD_BYTES];
+            while entries.len() < limit {
+                match reader.read_exact(&mut record) {
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                    read => read.context("read segment")?,
+                }
+                let (kind, offset, msg) = decode_record(&record);
+                if kind == APPEND && offset >= from && !self.removed.contains(&offset) {
+                    entries.push((offset, msg));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn remove(&mut self, offset: usize) -> anyhow::Result<()> {
+        self.write(REMOVE, offset, 0)?;
+        self.removed.insert(offset);
+        Ok(())
+    }
+
+    fn truncate(&mut self, offset: usize) -> anyhow::Result<()> {
+        self.write(TRUNCATE, offset, 0)?;
+        self.start = offset;
+        self.removed = self.removed.split_off(&offset);
+        // a segment can go once the next one starts at or below the offset. the last one always
+        // stays, it has the truncation record.
+        let passed: Vec<usize> = self
+            .segments
+            .keys()
+            .zip(self.segments.keys().skip(1))
+            .filter(|(_, next)| **next <= offset)
+            .map(|(base, _)| *base)
+            .collect();
+        for base in passed {
+            let segment = self.segments.remove(&base).expect("listed segments exist");
+            std::fs::remove_file(&segment.path).context("delete segment")?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        if let (true, Some(file)) = (self.dirty, &self.active) {
+            file.sync_data().context("sync segment")?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
 }
 
-// CommitedOffsetAndMessage
-struct COAM {
-    commited_offset: Option<usize>,
-    msgs: Vec<usize>,
+// entries compaction reads from a store at a time
+const COMPACT_PAGE: usize = 1024;
+
+struct Log {
+    // the entries below the end
+    store: Box<dyn Store>,
+    // entries past a gap, since they can reach followers out of order. they move to the store
+    // once the gap fills.
+    pending: BTreeMap<usize, usize>,
+    // compaction has run on the entries below this offset, and found the latest offset of each
+    // msg among them
+    compacted: usize,
+    latest: HashMap<usize, usize>,
 }
 
-impl COAM {
-    fn new() -> Self {
+impl Log {
+    fn new(store: Box<dyn Store>) -> Self {
         Self {
-            commited_offset: None,
-            msgs: Vec::new(),
+            pending: BTreeMap::new(),
+            compacted: store.start(),
+            latest: HashMap::new(),
+            store,
+        }
+    }
+
+    // entries we already have, or that retention dropped, are late copies and are left alone.
+    fn insert(&mut self, offset: usize, msg: usize) -> anyhow::Result<()> {
+        if offset < self.store.end() {
+            return Ok(());
+        }
+        self.pending.insert(offset, msg);
+        while let Some(msg) = self.pending.remove(&self.store.end()) {
+            self.store.append(self.store.end(), msg)?;
+        }
+        Ok(())
+    }
+
+    // drops every entry below the offset. only entries we have all of can go, so the start
+    // never passes the end.
+    fn truncate(&mut self, offset: usize) -> anyhow::Result<()> {
+        let offset = offset.min(self.store.end());
+        if offset <= self.store.start() {
+            return Ok(());
+        }
+        self.store.truncate(offset)?;
+        self.latest.retain(|_, o| *o >= offset);
+        Ok(())
+    }
+
+    // removes the entries that a later offset of the same msg supersedes.
+    fn compact(&mut self) -> anyhow::Result<()> {
+        let mut from = self.compacted.max(self.store.start());
+        let mut superseded = Vec::new();
+        loop {
+            let page = self.store.read(from, COMPACT_PAGE)?;
+            for (offset, msg) in &page {
+                if let Some(previous) = self.latest.insert(*msg, *offset) {
+                    superseded.push(previous);
+                }
+            }
+            match page.last() {
+                Some((offset, _)) if page.len() == COMPACT_PAGE => from = offset + 1,
+                _ => break,
+            }
+        }
+        for offset in superseded {
+            self.store.remove(offset)?;
         }
+        self.compacted = self.store.end();
+        Ok(())
+    }
+
+    // drops the oldest entries beyond the most the log may keep.
+    fn cap(&mut self, max: usize) -> anyhow::Result<()> {
+        let excess = self.store.len().saturating_sub(max);
+        if excess == 0 {
+            return Ok(());
+        }
+        let oldest = self.store.read(self.store.start(), excess + 1)?;
+        let keep = oldest.get(excess).map_or(self.store.end(), |(o, _)| *o);
+        self.truncate(keep)
     }
 }
 
+// (offset, msg) pairs by key
+type Polled = HashMap<String, Vec<(usize, usize)>>;
+
+// the (key, offset) entries of an atomic batch
+type AtomicBatch = Rc<Vec<(String, usize)>>;
+
 struct Messages {
-    map: HashMap<String, COAM>,
+    storage: Storage,
+    map: HashMap<String, Log>,
+    // by consumer group, none for the offsets shared by all clients. kept apart from the logs:
+    // commits gossiped from other nodes can arrive before the key's messages do. they aren't
+    // stored, a restarted node learns them from later commits.
+    commited: HashMap<Option<String>, HashMap<String, usize>>,
+    // the atomic batch of every entry that is part of one, and the batches we don't have every
+    // entry of yet. polls stop short of those, so that a batch shows up all at once.
+    atomic: HashMap<(String, usize), AtomicBatch>,
+    unstable: Vec<AtomicBatch>,
 }
 
 impl Messages {
-    fn new() -> Self {
-        Self {
-            map: HashMap::new(),
+    // picks up the logs the storage already has.
+    fn open(storage: Storage, node: &str) -> anyhow::Result<Self> {
+        let storage = match storage {
+            Storage::Segments { dir, fsync } => Storage::Segments {
+                dir: dir.join(format!("kafka-{}", node)),
+                fsync,
+            },
+            storage => storage,
+        };
+        let mut map = HashMap::new();
+        if let Storage::Segments { dir, .. } = &storage {
+            std::fs::create_dir_all(dir).context("create storage directory")?;
+            for entry in std::fs::read_dir(dir).context("list logs")? {
+                let entry = entry.context("list logs")?;
+                let Some(key) = entry.file_name().to_str().and_then(dir_key) else {
+                    continue;
+                };
+                let log = Log::new(storage.open(&key)?);
+                map.insert(key, log);
+            }
         }
+        Ok(Self {
+            storage,
+            map,
+            commited: HashMap::new(),
+            atomic: HashMap::new(),
+            unstable: Vec::new(),
+        })
     }
 
-    fn add_msg(&mut self, key: String, msg: usize) -> usize {
-        self.map
-            .entry(key.clone())
-            .or_insert_with(|| COAM::new())
-            .msgs
-            .push(msg);
+    fn log(&mut self, key: String) -> anyhow::Result<&mut Log> {
+        match self.map.entry(key) {
+            Entry::Occupied(log) => Ok(log.into_mut()),
+            Entry::Vacant(log) => {
+                let store = self.storage.open(log.key())?;
+                Ok(log.insert(Log::new(store)))
+            }
+        }
+    }
 
-        match self.map.get(&key) {
-            Some(coam) => coam.msgs.len() - 1,
-            None => 0,
+    // appends to a key we lead and returns the offset it got.
+    fn add_msg(&mut self, key: String, msg: usize) -> anyhow::Result<usize> {
+        let log = self.log(key)?;
+        let offset = log.store.end();
+        log.insert(offset, msg)?;
+        Ok(offset)
+    }
+
+    // stores an entry whose offset was assigned elsewhere, or held for an atomic batch.
+    fn insert_msg(&mut self, key: String, offset: usize, msg: usize) -> anyhow::Result<()> {
+        self.log(key)?.insert(offset, msg)?;
+        self.settle();
+        Ok(())
+    }
+
+    // where the key's log ends, the offset the next append gets.
+    fn end(&self, key: &str) -> usize {
+        self.map.get(key).map_or(0, |log| log.store.end())
+    }
+
+    // whether we know of the atomic batch already.
+    fn knows_batch(&self, batch: &[(String, usize)]) -> bool {
+        batch
+            .first()
+            .is_some_and(|entry| self.atomic.contains_key(entry))
+    }
+
+    fn add_batch(&mut self, batch: Vec<(String, usize)>) {
+        if self.knows_batch(&batch) {
+            return;
+        }
+        let batch = Rc::new(batch);
+        for entry in batch.iter() {
+            self.atomic.insert(entry.clone(), batch.clone());
         }
+        self.unstable.push(batch);
+        self.settle();
     }
 
-    fn get_msgs(&self, offsets: &HashMap<String, usize>) -> HashMap<String, Vec<(usize, usize)>> {
-        offsets
-            .iter()
-            .filter_map(|(key, offset)| {
-                self.map.get(key).map(|coam| {
-                    (
-                        key.clone(),
-                        coam.msgs[*offset..]
-                            .iter()
-                            .enumerate()
-                            .map(|(i, m)| (offset + i, m.clone()))
-                            .collect(),
-                    )
-                })
-            })
+    // the atomic batches the entries are part of, for followers to hold them back as well.
+    fn batches_of(&self, entries: &[(String, usize, usize)]) -> Vec<Vec<(String, usize)>> {
+        let mut batches: Vec<&AtomicBatch> = Vec::new();
+        for (key, offset, _) in entries {
+            if let Some(batch) = self.atomic.get(&(key.clone(), *offset)) {
+                if !batches.iter().any(|b| Rc::ptr_eq(b, batch)) {
+                    batches.push(batch);
+                }
+            }
+        }
+        batches.into_iter().map(|b| b.to_vec()).collect()
+    }
+
+    // lets polls see the atomic batches we have every entry of by now.
+    fn settle(&mut self) {
+        let map = &self.map;
+        self.unstable.retain(|batch| {
+            !batch
+                .iter()
+                .all(|(key, offset)| map.get(key).is_some_and(|log| *offset < log.store.end()))
+        });
+    }
+
+    // where the logs of the keys end, for a restarted node to find what we miss.
+    fn ends(&self, keys: &[String]) -> HashMap<String, usize> {
+        keys.iter()
+            .filter_map(|key| Some((key.clone(), self.map.get(key)?.store.end())))
             .collect()
     }
 
-    fn insert_commited_offsets(&mut self, offsets: HashMap<String, usize>) {
+    // the entries a node whose logs end where given doesn't have, as far as we still have them.
+    fn missing(
+        &self,
+        ends: &HashMap<String, usize>,
+    ) -> anyhow::Result<Vec<(String, usize, usize)>> {
+        let mut missing = Vec::new();
+        for (key, log) in &self.map {
+            let end = ends.get(key).copied().unwrap_or_default();
+            let limit = log.store.end().saturating_sub(end);
+            for (offset, msg) in log.store.read(end, limit)? {
+                missing.push((key.clone(), offset, msg));
+            }
+        }
+        Ok(missing)
+    }
+
+    // whether the entry made it into the key's store, rather than waiting for a gap to fill.
+    fn has_msg(&self, key: &str, offset: usize) -> bool {
+        self.map
+            .get(key)
+            .is_some_and(|log| offset < log.store.end())
+    }
+
+    // polls can't ask for offsets retention dropped.
+    fn check_offsets(&self, offsets: &HashMap<String, usize>) -> Result<(), String> {
+        for (key, offset) in offsets {
+            let start = self.map.get(key).map_or(0, |log| log.store.start());
+            if *offset < start {
+                return Err(format!(
+                    "can't poll offset {} of {}, its log starts at {}",
+                    offset, key, start
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // the messages from each offset on that we have everything before, at most POLL_KEY_LIMIT
+    // per key and POLL_LIMIT in all. keys cut short get the offset to continue from. offsets
+    // beyond the end of a log just find nothing.
+    fn get_msgs(
+        &self,
+        offsets: &HashMap<String, usize>,
+    ) -> anyhow::Result<(Polled, HashMap<String, usize>)> {
+        let mut budget = POLL_LIMIT;
+        let mut msgs = HashMap::new();
+        let mut next_offsets = HashMap::new();
+        for (key, offset) in offsets {
+            let Some(log) = self.map.get(key) else {
+                continue;
+            };
+            let limit = budget.min(POLL_KEY_LIMIT);
+            // one more than fits tells whether there is more to poll
+            let mut page = log.store.read(*offset, limit + 1)?;
+            let unstable = self
+                .unstable
+                .iter()
+                .flat_map(|batch| batch.iter())
+                .filter(|(k, _)| k == key)
+                .map(|(_, o)| *o)
+                .min();
+            if let Some(unstable) = unstable {
+                page.retain(|(o, _)| *o < unstable);
+            }
+            if page.len() > limit {
+                page.truncate(limit);
+                let next = page.last().map_or(*offset, |(o, _)| o + 1);
+                next_offsets.insert(key.clone(), next);
+            }
+            budget -= page.len();
+            msgs.insert(key.clone(), page);
+        }
+        Ok((msgs, next_offsets))
+    }
+
+    // a client can only commit offsets of messages we have. on failure nothing is committed and
+    // the error names the first offset beyond its log's end.
+    fn commit_offsets(
+        &mut self,
+        group: &Option<String>,
+        offsets: &HashMap<String, usize>,
+    ) -> Result<(), String> {
         for (key, offset) in offsets {
-            match self.map.get_mut(&key) {
-                Some(coam) => coam.commited_offset = Some(offset),
-                None => unreachable!(),
+            let end = self.map.get(key).map_or(0, |log| log.store.end());
+            if *offset >= end {
+                return Err(format!(
+                    "can't commit offset {} of {}, its log ends at {}",
+                    offset, key, end
+                ));
             }
         }
+        self.insert_commited_offsets(group.clone(), offsets.clone());
+        Ok(())
+    }
+
+    // committed offsets only ever move forward, whatever order commits arrive in.
+    fn insert_commited_offsets(&mut self, group: Option<String>, offsets: HashMap<String, usize>) {
+        let commited = self.commited.entry(group).or_default();
+        for (key, offset) in offsets {
+            let commited = commited.entry(key).or_insert(offset);
+            *commited = (*commited).max(offset);
+        }
     }
 
-    fn get_commited_offsets(&self, keys: &Vec<String>) -> HashMap<String, usize> {
+    fn get_commited_offsets(
+        &self,
+        group: &Option<String>,
+        keys: &[String],
+    ) -> HashMap<String, usize> {
+        let Some(commited) = self.commited.get(group) else {
+            return HashMap::new();
+        };
         keys.iter()
-            .filter_map(|key| {
-                let commited_offset = self.map.get(key).map(|coam| coam.commited_offset);
-                match commited_offset {
-                    Some(Some(offset)) => Some((key.clone(), offset)),
-                    _ => None,
-                }
-            })
+            .filter_map(|key| Some((key.clone(), *commited.get(key)?)))
             .collect()
     }
+
+    // drops whatever the retention policies let go of.
+    fn retain(&mut self, retention: &Retention) -> anyhow::Result<()> {
+        for (key, log) in &mut self.map {
+            if retention.commited {
+                // groups that never committed the key don't hold it back
+                let commited = self.commited.values().filter_map(|c| c.get(key)).min();
+                if let Some(commited) = commited {
+                    log.truncate(*commited)?;
+                }
+            }
+            if retention.compact {
+                log.compact()?;
+            }
+            if let Some(max) = retention.max_entries() {
+                log.cap(max)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        for log in self.map.values_mut() {
+            log.store.sync()?;
+        }
+        Ok(())
+    }
 }
 
-impl Node<(), Payload, InjectedPayload> for KafkaNode {
+impl Node<Config, Payload, InjectedPayload> for KafkaNode {
     fn from_init(
-        _state: (),
+        config: Config,
         init: Init,
         tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
     ) -> anyhow::Result<Self> {
-        Ok(Self {
+        let ticker = tx.clone();
+        std::thread::spawn(move || {
+            // generate replicate events
+            // TODO: handle EOF signal
+            loop {
+                std::thread::sleep(REPLICATE_INTERVAL);
+                if ticker
+                    .send(Event::Injected(InjectedPayload::Replicate))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        if let Acks::Count(n) = config.acks {
+            if n > init.node_ids.len() {
+                anyhow::bail!(
+                    "can't wait for {} acks with {} nodes",
+                    n,
+                    init.node_ids.len()
+                );
+            }
+        }
+        if config.min_insync > init.node_ids.len() {
+            anyhow::bail!(
+                "can't keep {} nodes in sync with {} nodes",
+                config.min_insync,
+                init.node_ids.len()
+            );
+        }
+        let messages = Messages::open(config.storage, &init.node_id)?;
+        let (journal, records) = Journal::open(&messages.storage, "txns")?;
+        let (commit_journal, commits) = Journal::open(&messages.storage, "commits")?;
+        let (producer_journal, sequenced) = Journal::open(&messages.storage, "producers")?;
+        // the entries we were replicating before a restart are only known from what the other
+        // nodes already have
+        let recovering = if messages.map.is_empty() {
+            HashSet::new()
+        } else {
+            init.node_ids
+                .iter()
+                .filter(|n| *n != &init.node_id)
+                .cloned()
+                .collect()
+        };
+        let replicas = init
+            .node_ids
+            .iter()
+            .filter(|n| *n != &init.node_id)
+            .map(|n| {
+                let replica = Replica {
+                    pending: BTreeMap::new(),
+                    in_sync: true,
+                };
+                (n.clone(), replica)
+            })
+            .collect();
+        // transactions are named after batch ids, which mustn't come round again after a restart.
+        // the journal tells how far they got, without one they start from the clock.
+        let id = match messages.storage {
+            Storage::Memory => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .context("read the clock")?
+                .as_micros() as usize,
+            Storage::Segments { .. } => 1,
+        };
+        let mut node = Self {
             node: init.node_id.clone(),
-            id: 1,
-            messages: Messages::new(),
+            id,
+            mode: config.mode,
+            acks: config.acks,
+            min_insync: config.min_insync,
+            retention: config.retention,
+            messages,
             others: init
                 .node_ids
-                .into_iter()
-                .filter(|n| n != &init.node_id)
+                .iter()
+                .filter(|n| *n != &init.node_id)
+                .cloned()
                 .collect(),
+            node_ids: init.node_ids,
             tx,
-        })
+            forwarded: HashMap::new(),
+            replicas,
+            recovering,
+            stored: BTreeMap::new(),
+            producers: HashMap::new(),
+            producer_journal,
+            batches: HashMap::new(),
+            parts: HashMap::new(),
+            txns: HashMap::new(),
+            aborted: HashSet::new(),
+            locks: HashMap::new(),
+            decisions: HashMap::new(),
+            journal,
+            commit_journal,
+            allocations: HashMap::new(),
+            groups: HashMap::new(),
+            changed_commits: HashMap::new(),
+            changed_groups: HashMap::new(),
+            changes: 0,
+            changes_acked: HashMap::new(),
+        };
+        node.recover(records)?;
+        for CommitRecord { group, offsets } in commits {
+            node.messages.insert_commited_offsets(group, offsets);
+        }
+        // the latest commits of every group say it all
+        let latest: Vec<_> = node
+            .messages
+            .commited
+            .iter()
+            .map(|(group, offsets)| CommitRecord {
+                group: group.clone(),
+                offsets: offsets.clone(),
+            })
+            .collect();
+        node.commit_journal.rewrite(&latest)?;
+        node.recover_producers(sequenced)?;
+        Ok(node)
     }
 
     fn step(
@@ -160,91 +2716,536 @@ impl Node<(), Payload, InjectedPayload> for KafkaNode {
         match input {
             Event::EOF => {}
             Event::Injected(payload) => match payload {
-                InjectedPayload::GossipSend { key, msg } => {
-                    for n in &self.others {
-                        Message {
-                            src: self.node.clone(),
-                            dst: n.clone(),
-                            body: Body {
-                                id: None,
-                                in_reply_to: None,
-                                payload: Payload::GossipSend {
-                                    key: key.clone(),
-                                    msg,
-                                },
-                            },
-                        }
-                        .send(&mut *output)
-                        .with_context(|| format!("gossip to {}", n))?;
+                InjectedPayload::Allocate { key } => {
+                    if self.allocations.contains_key(&key) {
+                        self.allocate(&key, output)?;
                     }
                 }
-                InjectedPayload::GossipCommitOffsets { offsets } => {
+                InjectedPayload::Replicate => {
+                    let timed_out: Vec<_> = self
+                        .allocations
+                        .iter()
+                        .filter(|(_, a)| {
+                            a.request
+                                .is_some_and(|(_, sent)| sent.elapsed() >= ALLOCATE_TIMEOUT)
+                        })
+                        .map(|(key, _)| key.clone())
+                        .collect();
+                    for key in timed_out {
+                        self.allocate(&key, output)?;
+                    }
+
                     for n in &self.others {
-                        Message {
-                            src: self.node.clone(),
-                            dst: n.clone(),
-                            body: Body {
-                                id: None,
-                                in_reply_to: None,
-                                payload: Payload::GossipCommit {
-                                    offsets: offsets.clone(),
-                                },
-                            },
+                        self.catch_up(n, output)?;
+                    }
+                    if !self.recovering.is_empty() {
+                        let keys: Vec<_> = self.messages.map.keys().cloned().collect();
+                        for n in &self.recovering {
+                            let payload = Payload::ListEnds { keys: keys.clone() };
+                            self.send_to(n, None, payload, output)?;
+                        }
+                    }
+
+                    // followers that fall behind stop holding up sends, until they catch up
+                    let mut changed = false;
+                    for replica in self.replicas.values_mut() {
+                        let in_sync = replica.lag() < REPLICA_LAG;
+                        changed |= in_sync != replica.in_sync;
+                        replica.in_sync = in_sync;
+                    }
+                    if changed {
+                        let waiting: Vec<_> = self.stored.keys().cloned().collect();
+                        self.answer_stored(&waiting, output)?;
+                    }
+                    self.expire_stored(output)?;
+
+                    let now = Instant::now();
+                    let abandoned: Vec<_> = self
+                        .forwarded
+                        .iter()
+                        .filter(|(_, (_, sent))| now - *sent >= FORWARD_TIMEOUT)
+                        .map(|(id, _)| *id)
+                        .collect();
+                    for id in abandoned {
+                        let (mut client, _) = self.forwarded.remove(&id).expect("listed");
+                        // the leader may still have stored it, so the outcome is unknown
+                        client.body.payload = Payload::Error {
+                            code: error_code::TIMEOUT,
+                            text: "the key's leader didn't answer".to_string(),
+                        };
+                        client.send(&mut *output).context("reply to send")?;
+                    }
+                    for window in self.producers.values_mut() {
+                        for sequenced in window.values_mut() {
+                            let Sequenced::Allocating(retries) = sequenced else {
+                                continue;
+                            };
+                            let (expired, waiting) = std::mem::take(retries)
+                                .into_iter()
+                                .partition(|(_, arrived)| now - *arrived >= RETRY_TIMEOUT);
+                            *retries = waiting;
+                            for (mut retry, _) in expired {
+                                // the send may still get an offset, so the outcome is unknown
+                                retry.body.payload = Payload::Error {
+                                    code: error_code::TIMEOUT,
+                                    text: "the send is still waiting for an offset".to_string(),
+                                };
+                                retry.send(&mut *output).context("reply to send")?;
+                            }
                         }
-                        .send(&mut *output)
-                        .with_context(|| format!("gossip to {}", n))?;
                     }
+
+                    self.messages.retain(&self.retention)?;
+                    self.messages.sync()?;
+                    self.journal.sync()?;
+                    self.commit_journal.sync()?;
+
+                    expire_groups(&mut self.groups);
+                    expire_groups(&mut self.changed_groups);
+                    self.gossip_groups(output)?;
+
+                    self.check_batches(output)?;
+                    self.inquire(output)?;
                 }
             },
             Event::Message(input) => {
+                let in_reply_to = input.body.in_reply_to;
                 let mut reply = input.into_reply(Some(&mut self.id));
                 match reply.body.payload {
-                    Payload::GossipSend { key, msg } => {
-                        self.messages.add_msg(key, msg);
+                    Payload::Replicate { entries, batches } => {
+                        for batch in batches {
+                            if !self.messages.knows_batch(&batch) {
+                                self.journal.write(&TxnRecord::Batch {
+                                    batch: batch.clone(),
+                                })?;
+                                self.messages.add_batch(batch);
+                            }
+                        }
+                        for (key, offset, msg) in &entries {
+                            self.messages.insert_msg(key.clone(), *offset, *msg)?;
+                        }
+                        // entries waiting for a gap to fill aren't acknowledged, so the sender
+                        // keeps them until we can store them
+                        let acked = entries
+                            .into_iter()
+                            .filter(|(key, offset, _)| self.messages.has_msg(key, *offset))
+                            .map(|(key, offset, _)| (key, offset))
+                            .collect();
+                        reply.body.payload = Payload::ReplicateOk { entries: acked };
+                        reply.send(&mut *output).context("acknowledge replicate")?;
                     }
-                    Payload::GossipCommit { offsets } => {
-                        self.messages.insert_commited_offsets(offsets);
+                    Payload::ReplicateOk { entries } => {
+                        let Some(replica) = self.replicas.get_mut(&reply.dst) else {
+                            return Ok(());
+                        };
+                        for entry in &entries {
+                            replica.pending.remove(entry);
+                        }
+                        // a follower catching up after a partition gets the next batch right
+                        // away instead of one per tick. one that stored none of the last, waiting
+                        // for a gap to fill, gets it again on the next tick.
+                        if !replica.in_sync && !entries.is_empty() {
+                            self.catch_up(&reply.dst, output)?;
+                        }
+
+                        for entry in &entries {
+                            if let Some((_, stored_by, _)) = self.stored.get_mut(entry) {
+                                stored_by.insert(reply.dst.clone());
+                            }
+                        }
+                        self.answer_stored(&entries, output)?;
+                    }
+                    Payload::ListEnds { keys } => {
+                        reply.body.payload = Payload::ListEndsOk {
+                            ends: self.messages.ends(&keys),
+                        };
+                        reply.send(&mut *output).context("reply to list_ends")?;
+                    }
+                    Payload::ListEndsOk { ends } => {
+                        if !self.recovering.remove(&reply.dst) {
+                            return Ok(());
+                        }
+                        let Some(replica) = self.replicas.get_mut(&reply.dst) else {
+                            return Ok(());
+                        };
+                        let now = Instant::now();
+                        for (key, offset, msg) in self.messages.missing(&ends)? {
+                            replica.pending.entry((key, offset)).or_insert((msg, now));
+                        }
+                        self.catch_up(&reply.dst, output)?;
+                    }
+                    Payload::GossipGroups {
+                        seq,
+                        commits,
+                        groups,
+                    } => {
+                        for (group, offsets) in commits {
+                            self.commit_journal.write(&CommitRecord {
+                                group: group.clone(),
+                                offsets: offsets.clone(),
+                            })?;
+                            self.messages.insert_commited_offsets(group, offsets);
+                        }
+                        merge_groups(&mut self.groups, groups);
+                        reply.body.payload = Payload::GossipGroupsOk { seq };
+                        reply.send(&mut *output).context("acknowledge gossip")?;
+                    }
+                    Payload::GossipGroupsOk { seq } => {
+                        let acked = self.changes_acked.entry(reply.dst.clone()).or_default();
+                        *acked = seq.max(*acked);
+                        let everyone = self.others.iter().all(|n| {
+                            self.changes_acked.get(n).copied().unwrap_or_default() >= self.changes
+                        });
+                        if everyone {
+                            self.changed_commits.clear();
+                            self.changed_groups.clear();
+                        }
                     }
-                    Payload::Send { key, msg } => {
-                        let offset = self.messages.add_msg(key.clone(), msg);
+                    Payload::JoinGroup { group, keys } => {
+                        let member = reply.dst.clone();
+                        self.change_membership(&group, &member, false, output)?;
+                        let members = self.members(&group);
+                        let keys = keys
+                            .into_iter()
+                            .filter(|key| assignee(&members, key) == Some(&member))
+                            .collect();
 
-                        reply.body.payload = Payload::SendOk { offset };
-                        reply.send(&mut *output).context("reply to send")?;
+                        reply.body.payload = Payload::JoinGroupOk { members, keys };
+                        reply.send(&mut *output).context("reply to join_group")?;
+                    }
+                    Payload::LeaveGroup { group } => {
+                        let member = reply.dst.clone();
+                        self.change_membership(&group, &member, true, output)?;
+
+                        reply.body.payload = Payload::LeaveGroupOk;
+                        reply.send(&mut *output).context("reply to leave_group")?;
+                    }
+                    Payload::Send {
+                        ref key,
+                        msg,
+                        ref producer,
+                        seq,
+                    } => {
+                        let key = key.clone();
+                        let producer = producer.clone();
+                        // idempotent sends go through the key's leader in either mode, which sees
+                        // all their retries
+                        let idempotent = producer.is_some() && seq.is_some();
+                        let leader = self.leader(&key).clone();
+                        if leader != self.node && (idempotent || matches!(self.mode, Mode::Leader))
+                        {
+                            // the leader answers with the offset, which we pass on to the client
+                            let forward = Message {
+                                src: self.node.clone(),
+                                dst: leader.clone(),
+                                body: Body {
+                                    id: Some(self.id),
+                                    in_reply_to: None,
+                                    payload: Payload::Send {
+                                        key,
+                                        msg,
+                                        producer,
+                                        seq,
+                                    },
+                                },
+                            };
+                            forward
+                                .send(&mut *output)
+                                .with_context(|| format!("forward send to {}", leader))?;
+                            self.forwarded.insert(self.id, (reply, Instant::now()));
+                            self.id += 1;
+                            return Ok(());
+                        }
+
+                        if let Err(text) = self.check_insync().and(self.check_unlocked(&key)) {
+                            reply.body.payload = Payload::Error {
+                                code: error_code::TEMPORARILY_UNAVAILABLE,
+                                text,
+                            };
+                            reply.send(&mut *output).context("reply to send")?;
+                            return Ok(());
+                        }
+                        if let (Some(producer), Some(seq)) = (producer, seq) {
+                            match self.deduplicate(&key, producer, seq, reply, output)? {
+                                Some(send) => reply = send,
+                                None => return Ok(()),
+                            }
+                        }
+
+                        self.append(key, msg, Waiter::Send(reply), output)?;
+                    }
+                    Payload::SendBatch { ref msgs, atomic } => {
+                        let msgs = msgs.clone();
+                        let id = self.id;
+                        self.id += 1;
+                        // the entries each node appends, by their position in the batch
+                        let mut parts: HashMap<String, Vec<usize>> = HashMap::new();
+                        for (position, (key, _)) in msgs.iter().enumerate() {
+                            let owner = match self.mode {
+                                Mode::Leader => self.leader(key).clone(),
+                                Mode::LinKv => self.node.clone(),
+                            };
+                            parts.entry(owner).or_default().push(position);
+                        }
+
+                        if atomic && matches!(self.mode, Mode::LinKv) {
+                            let keys: HashSet<_> = msgs.iter().map(|(key, _)| key).collect();
+                            if keys.len() > 1 {
+                                reply.body.payload = Payload::Error {
+                                    code: error_code::NOT_SUPPORTED,
+                                    text: "atomic batches over several keys need key leaders"
+                                        .to_string(),
+                                };
+                                reply.send(&mut *output).context("reply to send_batch")?;
+                                return Ok(());
+                            }
+                        }
+                        if msgs.is_empty() {
+                            reply.body.payload = Payload::SendBatchOk { offsets: vec![] };
+                            reply.send(&mut *output).context("reply to send_batch")?;
+                            return Ok(());
+                        }
+
+                        let mut batch = Batch {
+                            reply,
+                            offsets: vec![None; msgs.len()],
+                            started: Instant::now(),
+                            txn: None,
+                        };
+                        // even a single leader goes through the commit, which tells the other
+                        // nodes to hold the batch back until they have all of it
+                        if atomic && matches!(self.mode, Mode::Leader) && msgs.len() > 1 {
+                            let leaders = parts.keys().cloned().collect();
+                            self.journal
+                                .write(&TxnRecord::Started { txn: id, leaders })?;
+                            batch.txn = Some(Txn {
+                                msgs: msgs.clone(),
+                                parts: parts.clone(),
+                                prepared: HashSet::new(),
+                            });
+                            self.batches.insert(id, batch);
+                            // our own part first, there is no need to ask anyone if it fails
+                            if let Some(positions) = parts.remove(&self.node) {
+                                let part = positions.iter().map(|p| msgs[*p].clone()).collect();
+                                match self.prepare(&self.node.clone(), id, part)? {
+                                    Payload::PrepareOk { offsets, .. } => {
+                                        self.prepared(id, &self.node.clone(), offsets, output)?;
+                                    }
+                                    error => return self.decide(id, Err(error), output),
+                                }
+                            }
+                            for (owner, positions) in parts {
+                                let part = positions.iter().map(|p| msgs[*p].clone()).collect();
+                                let payload = Payload::Prepare {
+                                    txn: id,
+                                    msgs: part,
+                                };
+                                self.send_part(&owner, id, positions, payload, output)?;
+                            }
+                            return Ok(());
+                        }
 
-                        self.tx
-                            .send(Event::Injected(InjectedPayload::GossipSend { key, msg }))?;
+                        // the leaders of other keys check for themselves
+                        let own = parts.get(&self.node).map(|positions| {
+                            self.check_insync().and_then(|()| {
+                                positions
+                                    .iter()
+                                    .try_for_each(|p| self.check_unlocked(&msgs[*p].0))
+                            })
+                        });
+                        if let Some(Err(text)) = own {
+                            batch.reply.body.payload = Payload::Error {
+                                code: error_code::TEMPORARILY_UNAVAILABLE,
+                                text,
+                            };
+                            batch
+                                .reply
+                                .send(&mut *output)
+                                .context("reply to send_batch")?;
+                            return Ok(());
+                        }
+                        self.batches.insert(id, batch);
+                        for (owner, positions) in parts {
+                            if owner == self.node && atomic && matches!(self.mode, Mode::LinKv) {
+                                // a single key, whose entries get offsets in one claim
+                                let key = msgs[positions[0]].0.clone();
+                                let sends = positions
+                                    .iter()
+                                    .map(|p| (Waiter::Batch(id, *p), msgs[*p].1))
+                                    .collect();
+                                self.take_offsets(key, sends, output)?;
+                                continue;
+                            }
+                            if owner == self.node {
+                                for position in positions {
+                                    let (key, msg) = msgs[position].clone();
+                                    self.append(key, msg, Waiter::Batch(id, position), output)?;
+                                }
+                                continue;
+                            }
+                            let part = positions.iter().map(|p| msgs[*p].clone()).collect();
+                            let payload = Payload::SendBatch {
+                                msgs: part,
+                                atomic: false,
+                            };
+                            self.send_part(&owner, id, positions, payload, output)?;
+                        }
+                    }
+                    Payload::SendBatchOk { offsets } => {
+                        if let Some((id, positions)) =
+                            in_reply_to.and_then(|id| self.parts.remove(&id))
+                        {
+                            self.fill_batch(id, &positions, &offsets, output)?;
+                        }
+                    }
+                    Payload::Prepare { txn, ref msgs } => {
+                        let msgs = msgs.clone();
+                        reply.body.payload = self.prepare(&reply.dst, txn, msgs)?;
+                        reply.send(&mut *output).context("reply to prepare")?;
+                    }
+                    Payload::PrepareOk { txn, ref offsets } => {
+                        let offsets = offsets.clone();
+                        self.prepared(txn, &reply.dst, offsets, output)?;
+                    }
+                    Payload::Commit { txn, ref batch } => {
+                        let id = (reply.dst.clone(), txn);
+                        match self.txns.get_mut(&id) {
+                            Some(Participant::Prepared(..)) => {
+                                let batch = batch.clone();
+                                self.commit_part(id, batch, Some(reply), output)?;
+                            }
+                            // answered once enough nodes stored the entries
+                            Some(Participant::Committing(_, waiting)) => *waiting = Some(reply),
+                            Some(Participant::Committed) => {
+                                reply.body.payload = Payload::CommitOk { txn };
+                                reply.send(&mut *output).context("reply to commit")?;
+                            }
+                            None => {
+                                reply.body.payload = match self.aborted.contains(&id) {
+                                    true => Payload::Error {
+                                        code: error_code::TXN_CONFLICT,
+                                        text: format!(
+                                            "transaction {} of {} was aborted",
+                                            txn, id.0
+                                        ),
+                                    },
+                                    // say a restart with memory storage lost it
+                                    false => Payload::Error {
+                                        code: error_code::CRASH,
+                                        text: format!(
+                                            "{} doesn't know transaction {} of {}",
+                                            self.node, txn, id.0
+                                        ),
+                                    },
+                                };
+                                reply.send(&mut *output).context("reply to commit")?;
+                            }
+                        }
+                    }
+                    Payload::CommitOk { txn } | Payload::AbortOk { txn } => {
+                        self.leader_done(txn, &reply.dst, Ok(()), output)?;
+                    }
+                    Payload::Abort { txn } => {
+                        reply.body.payload = self.abort_part(&(reply.dst.clone(), txn))?;
+                        reply.send(&mut *output).context("reply to abort")?;
+                    }
+                    Payload::Inquire { txn } => {
+                        // leaders hear of the decision on a batch we know of once it is made
+                        let known = self.decisions.contains_key(&txn)
+                            || self.batches.get(&txn).is_some_and(|b| b.txn.is_some());
+                        if !known {
+                            reply.body.payload = Payload::Abort { txn };
+                            reply.send(&mut *output).context("reply to inquire")?;
+                        }
+                    }
+                    payload @ (Payload::ReadOk { .. } | Payload::CasOk | Payload::Error { .. }) => {
+                        // the leader turning down a send we forwarded
+                        if let Some((mut client, _)) =
+                            in_reply_to.and_then(|id| self.forwarded.remove(&id))
+                        {
+                            client.body.payload = payload;
+                            client.send(&mut *output).context("reply to send")?;
+                            return Ok(());
+                        }
+                        // or part of a batch
+                        if let Some((id, _)) = in_reply_to.and_then(|id| self.parts.remove(&id)) {
+                            if self.decisions.contains_key(&id) {
+                                // a leader that couldn't carry out our decision
+                                return self.leader_done(id, &reply.dst, Err(payload), output);
+                            }
+                            if let Some(Batch { txn: Some(_), .. }) = self.batches.get(&id) {
+                                // a leader that won't prepare its part
+                                return self.decide(id, Err(payload), output);
+                            }
+                            return self.fail_batch(id, payload, output);
+                        }
+                        self.allocated(in_reply_to, payload, output)?;
+                    }
+                    Payload::SendOk { offset } => {
+                        let Some((mut client, _)) =
+                            in_reply_to.and_then(|id| self.forwarded.remove(&id))
+                        else {
+                            return Ok(());
+                        };
+                        client.body.payload = Payload::SendOk { offset };
+                        client.send(&mut *output).context("reply to send")?;
                     }
                     Payload::Poll { offsets } => {
-                        reply.body.payload = Payload::PollOk {
-                            msgs: self.messages.get_msgs(&offsets),
+                        reply.body.payload = match self.messages.check_offsets(&offsets) {
+                            Ok(()) => {
+                                let (msgs, next_offsets) = self.messages.get_msgs(&offsets)?;
+                                Payload::PollOk { msgs, next_offsets }
+                            }
+                            Err(text) => Payload::Error {
+                                code: OFFSET_OUT_OF_RANGE,
+                                text,
+                            },
                         };
                         reply.send(&mut *output).context("reply to poll")?;
                     }
-                    Payload::CommitOffsets { offsets } => {
-                        self.messages.insert_commited_offsets(offsets.clone());
+                    Payload::CommitOffsets { offsets, group } => {
+                        if let Err(text) = self.messages.commit_offsets(&group, &offsets) {
+                            reply.body.payload = Payload::Error {
+                                code: OFFSET_OUT_OF_RANGE,
+                                text,
+                            };
+                            reply
+                                .send(&mut *output)
+                                .context("reply to commit_offsets")?;
+                            return Ok(());
+                        }
+                        self.commit_journal.write(&CommitRecord {
+                            group: group.clone(),
+                            offsets: offsets.clone(),
+                        })?;
 
                         reply.body.payload = Payload::CommitOffsetsOk;
                         reply
                             .send(&mut *output)
                             .context("reply to commit_offsets")?;
 
-                        self.tx
-                            .send(Event::Injected(InjectedPayload::GossipCommitOffsets {
-                                offsets,
-                            }))?;
+                        let changed = self.changed_commits.entry(group).or_default();
+                        for (key, offset) in offsets {
+                            let changed = changed.entry(key).or_insert(offset);
+                            *changed = offset.max(*changed);
+                        }
+                        self.changes += 1;
+                        self.gossip_groups(output)?;
                     }
-                    Payload::ListCommittedOffsets { keys } => {
-                        let offsets = self.messages.get_commited_offsets(&keys);
+                    Payload::ListCommittedOffsets { keys, group } => {
+                        let offsets = self.messages.get_commited_offsets(&group, &keys);
 
                         reply.body.payload = Payload::ListCommittedOffsetsOk { offsets };
                         reply
                             .send(&mut *output)
                             .context("reply to list_committed_offsets")?;
                     }
-                    Payload::SendOk { .. }
-                    | Payload::PollOk { .. }
+                    Payload::PollOk { .. }
                     | Payload::CommitOffsetsOk
-                    | Payload::ListCommittedOffsetsOk { .. } => {}
+                    | Payload::ListCommittedOffsetsOk { .. }
+                    | Payload::JoinGroupOk { .. }
+                    | Payload::LeaveGroupOk
+                    | Payload::WriteOk => {}
                 }
             }
         }
@@ -253,5 +3254,153 @@ impl Node<(), Payload, InjectedPayload> for KafkaNode {
 }
 
 fn main() -> anyhow::Result<()> {
-    main_loop::<_, KafkaNode, _, _>(())
+    let mode = match std::env::var("KAFKA_MODE") {
+        Ok(mode) => mode.parse()?,
+        Err(_) => Mode::Leader,
+    };
+    let retention = match std::env::var("KAFKA_RETENTION") {
+        Ok(retention) => retention.parse()?,
+        Err(_) => Retention::default(),
+    };
+    let storage = match std::env::var("KAFKA_STORAGE").as_deref() {
+        Ok("memory") | Err(_) => Storage::Memory,
+        Ok("segments") => Storage::Segments {
+            // a directory shared by earlier runs would have their logs in it
+            dir: std::env::var("KAFKA_STORAGE_DIR")
+                .map(PathBuf::from)
+                .context("segments storage needs KAFKA_STORAGE_DIR")?,
+            fsync: match std::env::var("KAFKA_FSYNC") {
+                Ok(fsync) => fsync.parse()?,
+                Err(_) => Fsync::Interval,
+            },
+        },
+        Ok(storage) => anyhow::bail!("unknown kafka storage {}", storage),
+    };
+    let acks = match std::env::var("KAFKA_ACKS") {
+        Ok(acks) => acks.parse()?,
+        Err(_) => Acks::Count(1),
+    };
+    let min_insync = match std::env::var("KAFKA_MIN_INSYNC") {
+        Ok(min) => min.parse().context("parse KAFKA_MIN_INSYNC")?,
+        Err(_) => 1,
+    };
+    main_loop::<_, KafkaNode, _, _>(Config {
+        mode,
+        acks,
+        min_insync,
+        retention,
+        storage,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fresh directory for a store, removed again once the test is done with it
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "kafka-segments-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+
+        fn open(&self) -> SegmentStore {
+            SegmentStore::open(self.0.clone(), Fsync::Never).unwrap()
+        }
+
+        fn segments(&self) -> Vec<PathBuf> {
+            let mut paths: Vec<_> = std::fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+            paths.sort();
+            paths
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn torn_writes_are_cut_off() {
+        let dir = TempDir::new("torn");
+        let mut store = dir.open();
+        for offset in 0..3 {
+            store.append(offset, offset * 10).unwrap();
+        }
+        drop(store);
+        let segment = dir.segments().pop().unwrap();
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&encode_record(APPEND, 3, 30)[..RECORD_BYTES / 2])
+            .unwrap();
+        drop(file);
+
+        let mut store = dir.open();
+        assert_eq!(store.end(), 3);
+        let length = std::fs::metadata(&segment).unwrap().len();
+        assert_eq!(length, 3 * RECORD_BYTES as u64);
+        // what comes after lines up with the records before it
+        store.append(3, 30).unwrap();
+        drop(store);
+        let store = dir.open();
+        assert_eq!(
+            store.read(0, 10).unwrap(),
+            vec![(0, 0), (1, 10), (2, 20), (3, 30)]
+        );
+    }
+
+    #[test]
+    fn removals_and_truncations_are_replayed() {
+        let dir = TempDir::new("replay");
+        let mut store = dir.open();
+        for offset in 0..6 {
+            store.append(offset, offset).unwrap();
+        }
+        store.remove(1).unwrap();
+        store.remove(4).unwrap();
+        store.truncate(2).unwrap();
+        drop(store);
+
+        let store = dir.open();
+        assert_eq!((store.start(), store.end(), store.len()), (2, 6, 3));
+        assert_eq!(store.read(0, 10).unwrap(), vec![(2, 2), (3, 3), (5, 5)]);
+    }
+
+    #[test]
+    fn full_segments_roll_over() {
+        let dir = TempDir::new("rollover");
+        let mut store = dir.open();
+        for offset in 0..SEGMENT_RECORDS + 2 {
+            store.append(offset, offset).unwrap();
+        }
+        let names: Vec<_> = dir
+            .segments()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+            .collect();
+        let second = format!("{:020}.log", SEGMENT_RECORDS);
+        assert_eq!(names, vec![format!("{:020}.log", 0), second]);
+
+        // truncating past the first segment deletes it
+        store.truncate(SEGMENT_RECORDS + 1).unwrap();
+        assert_eq!(dir.segments().len(), 1);
+        drop(store);
+        let store = dir.open();
+        assert_eq!(
+            (store.start(), store.end()),
+            (SEGMENT_RECORDS + 1, SEGMENT_RECORDS + 2)
+        );
+        let last = SEGMENT_RECORDS + 1;
+        assert_eq!(store.read(0, 10).unwrap(), vec![(last, last)]);
+    }
 }
@@ -1,8 +1,76 @@
 use distributed::*;
 
 use anyhow::Context;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::io::StdoutLock;
+use std::{
+    fs::File,
+    io::{StdoutLock, Write},
+    ops::Range,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// snowflake ids count milliseconds from 2020-01-01, which lasts for 69 years in 41 bits.
+const SNOWFLAKE_EPOCH_MS: u64 = 1_577_836_800_000;
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const ULID_RANDOM: u128 = (1 << 80) - 1;
+// how many legacy ids are leased at a time. a crash skips whatever was left of the lease.
+const LEASE_BLOCK: u64 = 1000;
+const LIN_KV: &str = "lin-kv";
+const SEQUENCE_KEY: &str = "unique-ids-sequence";
+// how many sequence numbers a node claims from lin-kv at a time
+const SEQUENCE_BLOCK: u64 = 1000;
+// the next block is claimed once this few numbers are left, so generate rarely has to wait
+const SEQUENCE_LOW_WATER: u64 = 200;
+// lin-kv requests that go unanswered this long, say during a partition, are sent again
+const SEQUENCE_TIMEOUT: Duration = Duration::from_millis(500);
+// the most ids a generate_batch may ask for. a batch from the sequence has to fit in one block.
+const MAX_BATCH: u64 = SEQUENCE_BLOCK;
+
+enum Mode {
+    // <node>-<counter>
+    Legacy,
+    // 64-bit numbers of timestamp, node index and per-millisecond sequence
+    Snowflake,
+    Ulid,
+    UuidV7,
+    // numbers from one cluster-wide sequence, claimed from lin-kv in blocks
+    Sequence,
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "legacy" => Ok(Mode::Legacy),
+            "snowflake" => Ok(Mode::Snowflake),
+            "ulid" => Ok(Mode::Ulid),
+            "uuidv7" => Ok(Mode::UuidV7),
+            "sequence" => Ok(Mode::Sequence),
+            _ => anyhow::bail!("unknown id mode {}", s),
+        }
+    }
+}
+
+struct Config {
+    mode: Mode,
+    // where legacy ids keep their lease, so a restarted node carries on after the ids it handed
+    // out. there is no default: nodes of unrelated runs reuse the same ids and must not share it.
+    // without one the lease is only kept in memory, and a restarted node starts over.
+    state_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Guid {
+    Number(u64),
+    Text(String),
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -11,45 +79,596 @@ enum Payload {
     Generate,
     GenerateOk {
         #[serde(rename = "id")]
-        guid: String,
+        guid: Guid,
+    },
+    GenerateBatch {
+        count: serde_json::Value,
+    },
+    GenerateBatchOk {
+        ids: Vec<Guid>,
     },
+    ReadOk {
+        value: u64,
+    },
+    CasOk,
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+enum InjectedPayload {
+    Tick,
+}
+
+// a counter that never repeats, even across restarts. the end of the current block is persisted
+// before any number from it is handed out, and a restarted node continues from there.
+struct Lease {
+    // none if the lease is only kept in memory
+    path: Option<PathBuf>,
+    next: u64,
+    end: u64,
+}
+
+impl Lease {
+    fn in_memory() -> Self {
+        Self {
+            path: None,
+            next: 0,
+            end: 0,
+        }
+    }
+
+    fn open(path: PathBuf) -> anyhow::Result<Self> {
+        let end = match std::fs::read_to_string(&path) {
+            Ok(end) => end
+                .trim()
+                .parse()
+                .with_context(|| format!("corrupt lease in {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e).with_context(|| format!("read lease from {}", path.display())),
+        };
+        Ok(Self {
+            path: Some(path),
+            next: end,
+            end,
+        })
+    }
+
+    fn next(&mut self) -> anyhow::Result<u64> {
+        if self.next == self.end {
+            self.extend()?;
+        }
+        self.next += 1;
+        Ok(self.next - 1)
+    }
+
+    // writes the new end to a temporary file and renames it over the old one, so a crash leaves
+    // either lease behind but never half of one.
+    fn extend(&mut self) -> anyhow::Result<()> {
+        let end = self.end + LEASE_BLOCK;
+        let Some(path) = &self.path else {
+            self.end = end;
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp).context("create lease file")?;
+        writeln!(file, "{}", end).context("write lease")?;
+        file.sync_all().context("sync lease")?;
+        std::fs::rename(&tmp, path).context("replace lease")?;
+        if let Some(dir) = path.parent() {
+            File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .context("sync lease directory")?;
+        }
+        self.end = end;
+        Ok(())
+    }
+}
+
+// milliseconds since the unix epoch that never go backwards, even if the system clock does. after
+// a regression we keep counting from the last timestamp handed out until the clock catches up.
+#[derive(Default)]
+struct Clock {
+    last_ms: u64,
+}
+
+impl Clock {
+    fn now(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("clock is before the unix epoch")
+            .as_millis() as u64;
+        self.last_ms = self.last_ms.max(now);
+        self.last_ms
+    }
+
+    // moves on to the next millisecond once everything in the current one has been used up.
+    fn borrow(&mut self) -> u64 {
+        self.last_ms += 1;
+        self.last_ms
+    }
+}
+
+struct Snowflake {
+    clock: Clock,
+    node_index: u64,
+    ms: u64,
+    sequence: u64,
+}
+
+impl Snowflake {
+    fn new(node_index: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            node_index < 1 << NODE_BITS,
+            "snowflake ids only fit {} nodes",
+            1 << NODE_BITS
+        );
+        Ok(Self {
+            clock: Clock::default(),
+            node_index: node_index as u64,
+            ms: 0,
+            sequence: 0,
+        })
+    }
+
+    fn next(&mut self) -> u64 {
+        let now = self.clock.now();
+        if now > self.ms {
+            self.ms = now;
+            self.sequence = 0;
+        } else if self.sequence + 1 < 1 << SEQUENCE_BITS {
+            self.sequence += 1;
+        } else {
+            self.ms = self.clock.borrow();
+            self.sequence = 0;
+        }
+        ((self.ms - SNOWFLAKE_EPOCH_MS) << (NODE_BITS + SEQUENCE_BITS))
+            | (self.node_index << SEQUENCE_BITS)
+            | self.sequence
+    }
+}
+
+// 48 bits of timestamp and 80 random bits. ids made in the same millisecond increment the random
+// part so they stay sorted. if that runs out, we wait for the next millisecond instead of carrying
+// into the timestamp, which would put the next id of the real millisecond below this one.
+#[derive(Default)]
+struct Ulid {
+    clock: Clock,
+    last: u128,
+}
+
+impl Ulid {
+    fn next(&mut self) -> String {
+        let mut now = self.clock.now() as u128;
+        while now == self.last >> 80 && self.last & ULID_RANDOM == ULID_RANDOM {
+            std::thread::sleep(Duration::from_micros(100));
+            now = self.clock.now() as u128;
+        }
+        let random: u128 = rand::thread_rng().gen::<u128>() & ULID_RANDOM;
+        self.last = if now == self.last >> 80 {
+            self.last + 1
+        } else {
+            now << 80 | random
+        };
+        (0..26)
+            .rev()
+            .map(|i| CROCKFORD[(self.last >> (5 * i) & 0x1f) as usize] as char)
+            .collect()
+    }
+}
+
+// 48 bits of timestamp, the version, 12 bits counting ids made in the same millisecond, the
+// variant and 62 random bits.
+#[derive(Default)]
+struct UuidV7 {
+    clock: Clock,
+    ms: u64,
+    counter: u16,
+}
+
+impl UuidV7 {
+    fn next(&mut self) -> String {
+        let now = self.clock.now();
+        if now > self.ms {
+            self.ms = now;
+            self.counter = 0;
+        } else if self.counter + 1 < 1 << 12 {
+            self.counter += 1;
+        } else {
+            self.ms = self.clock.borrow();
+            self.counter = 0;
+        }
+        let random: u64 = rand::thread_rng().gen::<u64>() & ((1 << 62) - 1);
+        let uuid = (self.ms as u128) << 80
+            | 0x7 << 76
+            | (self.counter as u128) << 64
+            | 0b10 << 62
+            | random as u128;
+        let hex = format!("{:032x}", uuid);
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+}
+
+// where we are in claiming the next block of the sequence.
+enum Claim {
+    // reading where the sequence stands
+    Reading,
+    // moving the sequence on past the block starting at the value read
+    Advancing(u64),
+}
+
+// blocks of the cluster-wide sequence. lin-kv holds where the next free block starts and nodes
+// claim one by moving it on with a compare-and-set, so no two nodes ever get the same block and
+// later claims get larger numbers.
+struct Sequence {
+    current: Range<u64>,
+    spare: Option<Range<u64>>,
+    // the request claiming the next block, with its message id and when it was sent
+    claim: Option<(Claim, usize, Instant)>,
+    // generate requests waiting for a block, with how many ids they need
+    waiting: Vec<(Message<Payload>, u64)>,
+}
+
+impl Sequence {
+    fn next(&mut self) -> Option<u64> {
+        if self.current.is_empty() {
+            self.current = self.spare.take()?;
+        }
+        self.current.next()
+    }
+
+    // takes count numbers if we hold that many, otherwise none at all.
+    fn take(&mut self, count: u64) -> Option<Vec<u64>> {
+        if self.remaining() < count {
+            return None;
+        }
+        (0..count).map(|_| self.next()).collect()
+    }
+
+    fn remaining(&self) -> u64 {
+        let spare = self
+            .spare
+            .as_ref()
+            .map_or(0, |spare| spare.end - spare.start);
+        self.current.end - self.current.start + spare
+    }
+
+    fn send(
+        &mut self,
+        claim: Claim,
+        node: &str,
+        id: &mut usize,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let request = match claim {
+            Claim::Reading => KvRequest::Read {
+                key: SEQUENCE_KEY.to_string(),
+            },
+            Claim::Advancing(start) => KvRequest::Cas {
+                key: SEQUENCE_KEY.to_string(),
+                from: start,
+                to: start + SEQUENCE_BLOCK,
+                // the sequence starts out at 0
+                create_if_not_exists: start == 0,
+            },
+        };
+        Message {
+            src: node.to_string(),
+            dst: LIN_KV.to_string(),
+            body: Body {
+                id: Some(*id),
+                in_reply_to: None,
+                payload: request,
+            },
+        }
+        .send(&mut *output)
+        .context("send request to lin-kv")?;
+        self.claim = Some((claim, *id, Instant::now()));
+        *id += 1;
+        Ok(())
+    }
+
+    // claims another block unless we still have plenty or are already claiming one.
+    fn refill(
+        &mut self,
+        node: &str,
+        id: &mut usize,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let plenty = self.remaining() > SEQUENCE_LOW_WATER && self.waiting.is_empty();
+        if self.claim.is_some() || self.spare.is_some() || plenty {
+            return Ok(());
+        }
+        self.send(Claim::Reading, node, id, output)
+    }
+}
+
+// answers a generate or generate_batch request with the ids made for it.
+fn answer(
+    mut reply: Message<Payload>,
+    mut ids: Vec<Guid>,
+    output: &mut StdoutLock,
+) -> anyhow::Result<()> {
+    reply.body.payload = match reply.body.payload {
+        Payload::Generate => Payload::GenerateOk {
+            guid: ids.pop().expect("one id was generated"),
+        },
+        _ => Payload::GenerateBatchOk { ids },
+    };
+    reply.send(output).context("send response to generate")
+}
+
+enum Generator {
+    Legacy(Lease),
+    Snowflake(Snowflake),
+    Ulid(Ulid),
+    UuidV7(UuidV7),
+    Sequence(Sequence),
 }
 
 struct UniqueIdNode {
     node: String,
     id: usize,
+    generator: Generator,
 }
 
-impl Node<(), Payload> for UniqueIdNode {
+impl Node<Config, Payload, InjectedPayload> for UniqueIdNode {
     fn from_init(
-        _state: (),
+        config: Config,
         init: Init,
-        _tx: std::sync::mpsc::Sender<Event<Payload>>,
+        tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
     ) -> anyhow::Result<Self> {
+        let generator = match config.mode {
+            Mode::Legacy => match config.state_dir {
+                Some(state_dir) => {
+                    std::fs::create_dir_all(&state_dir).context("create state directory")?;
+                    let path = state_dir.join(format!("unique-ids-{}.lease", init.node_id));
+                    Generator::Legacy(Lease::open(path)?)
+                }
+                None => Generator::Legacy(Lease::in_memory()),
+            },
+            Mode::Snowflake => {
+                let node_index = init
+                    .node_ids
+                    .iter()
+                    .position(|n| n == &init.node_id)
+                    .context("node is not part of the cluster")?;
+                Generator::Snowflake(Snowflake::new(node_index)?)
+            }
+            Mode::Ulid => Generator::Ulid(Ulid::default()),
+            Mode::UuidV7 => Generator::UuidV7(UuidV7::default()),
+            Mode::Sequence => {
+                std::thread::spawn(move || {
+                    // generate tick events to resend lost lin-kv requests
+                    // TODO: handle EOF signal
+                    loop {
+                        std::thread::sleep(SEQUENCE_TIMEOUT);
+                        if tx.send(Event::Injected(InjectedPayload::Tick)).is_err() {
+                            break;
+                        }
+                    }
+                });
+                Generator::Sequence(Sequence {
+                    current: 0..0,
+                    spare: None,
+                    claim: None,
+                    waiting: Vec::new(),
+                })
+            }
+        };
         Ok(UniqueIdNode {
             node: init.node_id,
             id: 1,
+            generator,
         })
     }
 
-    fn step(&mut self, input: Event<Payload>, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let Event::Message(input) = input else {
-            panic!("got injected event when there's no event injection");
+    fn step(
+        &mut self,
+        input: Event<Payload, InjectedPayload>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let input = match input {
+            Event::Message(input) => input,
+            Event::Injected(InjectedPayload::Tick) => {
+                if let Generator::Sequence(sequence) = &mut self.generator {
+                    let timed_out = |(_, _, sent): &mut (Claim, usize, Instant)| {
+                        sent.elapsed() >= SEQUENCE_TIMEOUT
+                    };
+                    if let Some((claim, _, _)) = sequence.claim.take_if(timed_out) {
+                        sequence.send(claim, &self.node, &mut self.id, output)?;
+                    }
+                }
+                return Ok(());
+            }
+            Event::EOF => return Ok(()),
         };
 
+        let in_reply_to = input.body.in_reply_to;
         let mut reply = input.into_reply(Some(&mut self.id));
         match reply.body.payload {
-            Payload::Generate => {
-                let guid = format!("{}-{}", self.node, self.id);
-                reply.body.payload = Payload::GenerateOk { guid };
-                reply.send(output).context("send response to generate")?;
+            Payload::Generate | Payload::GenerateBatch { .. } => {
+                let count = match &reply.body.payload {
+                    Payload::GenerateBatch { count } => count.as_u64(),
+                    _ => Some(1),
+                };
+                let Some(count) = count.filter(|count| (1..=MAX_BATCH).contains(count)) else {
+                    reply.body.payload = Payload::Error {
+                        code: error_code::MALFORMED_REQUEST,
+                        text: format!("can only generate between 1 and {} ids at once", MAX_BATCH),
+                    };
+                    reply.send(output).context("reject batch")?;
+                    return Ok(());
+                };
+                let ids = match &mut self.generator {
+                    Generator::Legacy(lease) => (0..count)
+                        .map(|_| Ok(Guid::Text(format!("{}-{}", self.node, lease.next()?))))
+                        .collect::<anyhow::Result<_>>()?,
+                    Generator::Snowflake(snowflake) => {
+                        (0..count).map(|_| Guid::Number(snowflake.next())).collect()
+                    }
+                    Generator::Ulid(ulid) => (0..count).map(|_| Guid::Text(ulid.next())).collect(),
+                    Generator::UuidV7(uuid) => {
+                        (0..count).map(|_| Guid::Text(uuid.next())).collect()
+                    }
+                    Generator::Sequence(sequence) => {
+                        // requests are served in order, so none may overtake one that is waiting
+                        let ids = if sequence.waiting.is_empty() {
+                            sequence.take(count)
+                        } else {
+                            None
+                        };
+                        let Some(ids) = ids else {
+                            sequence.waiting.push((reply, count));
+                            sequence.refill(&self.node, &mut self.id, output)?;
+                            return Ok(());
+                        };
+                        sequence.refill(&self.node, &mut self.id, output)?;
+                        ids.into_iter().map(Guid::Number).collect()
+                    }
+                };
+                answer(reply, ids, output)?;
             }
-            Payload::GenerateOk { .. } => {}
+            Payload::ReadOk { .. } | Payload::CasOk | Payload::Error { .. } => {
+                let Generator::Sequence(sequence) = &mut self.generator else {
+                    return Ok(());
+                };
+                // answers to requests we have given up on and sent again are ignored
+                let Some((claim, _, _)) = sequence
+                    .claim
+                    .take_if(|(_, msg_id, _)| in_reply_to == Some(*msg_id))
+                else {
+                    return Ok(());
+                };
+                match (claim, reply.body.payload) {
+                    (Claim::Reading, Payload::ReadOk { value }) => {
+                        sequence.send(Claim::Advancing(value), &self.node, &mut self.id, output)?;
+                    }
+                    (Claim::Reading, Payload::Error { code, .. })
+                        if code == error_code::KEY_DOES_NOT_EXIST =>
+                    {
+                        sequence.send(Claim::Advancing(0), &self.node, &mut self.id, output)?;
+                    }
+                    (Claim::Advancing(start), Payload::CasOk) => {
+                        sequence.spare = Some(start..start + SEQUENCE_BLOCK);
+                        while let Some((_, count)) = sequence.waiting.first() {
+                            let Some(ids) = sequence.take(*count) else {
+                                break;
+                            };
+                            let (waiting, _) = sequence.waiting.remove(0);
+                            answer(waiting, ids.into_iter().map(Guid::Number).collect(), output)?;
+                        }
+                        sequence.refill(&self.node, &mut self.id, output)?;
+                    }
+                    // someone else claimed the block first, or lin-kv failed: look again
+                    _ => sequence.send(Claim::Reading, &self.node, &mut self.id, output)?,
+                }
+            }
+            Payload::GenerateOk { .. } | Payload::GenerateBatchOk { .. } => {}
         }
         Ok(())
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    main_loop::<_, UniqueIdNode, _, _>(())
+    let mode = match std::env::var("UNIQUE_IDS_MODE") {
+        Ok(mode) => mode.parse()?,
+        Err(_) => Mode::Legacy,
+    };
+    let state_dir = std::env::var("UNIQUE_IDS_STATE_DIR").ok().map(PathBuf::from);
+    main_loop::<_, UniqueIdNode, _, _>(Config { mode, state_dir })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now_ms() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    fn decode_ulid(ulid: &str) -> u128 {
+        ulid.bytes().fold(0, |value, c| {
+            let digit = CROCKFORD.iter().position(|d| *d == c).unwrap();
+            value << 5 | digit as u128
+        })
+    }
+
+    #[test]
+    fn snowflake_ids_hold_timestamp_node_and_sequence() {
+        let mut snowflake = Snowflake::new(5).unwrap();
+        let before = now_ms();
+        let ids: Vec<_> = (0..3).map(|_| snowflake.next()).collect();
+        let after = now_ms();
+        for id in &ids {
+            let ms = (id >> (NODE_BITS + SEQUENCE_BITS)) + SNOWFLAKE_EPOCH_MS;
+            assert!((before..=after).contains(&ms));
+            assert_eq!(id >> SEQUENCE_BITS & ((1 << NODE_BITS) - 1), 5);
+        }
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        assert!(Snowflake::new(1 << NODE_BITS).is_err());
+    }
+
+    #[test]
+    fn snowflake_sequences_move_on_to_the_next_millisecond_once_used_up() {
+        let mut snowflake = Snowflake::new(0).unwrap();
+        let first = snowflake.next();
+        snowflake.sequence = (1 << SEQUENCE_BITS) - 1;
+        let next = snowflake.next();
+        assert!(next > first);
+        assert_eq!(next & ((1 << SEQUENCE_BITS) - 1), 0);
+    }
+
+    #[test]
+    fn uuidv7_ids_hold_timestamp_version_and_variant() {
+        let mut uuid = UuidV7::default();
+        let before = now_ms();
+        let ids: Vec<_> = (0..3).map(|_| uuid.next()).collect();
+        let after = now_ms();
+        for id in &ids {
+            let groups: Vec<_> = id.split('-').map(str::len).collect();
+            assert_eq!(groups, vec![8, 4, 4, 4, 12]);
+            let value = u128::from_str_radix(&id.replace('-', ""), 16).unwrap();
+            assert!((before..=after).contains(&((value >> 80) as u64)));
+            assert_eq!(value >> 76 & 0xf, 7);
+            assert_eq!(value >> 62 & 0b11, 0b10);
+        }
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn ulids_hold_the_timestamp_and_stay_sorted() {
+        let mut ulid = Ulid::default();
+        let before = now_ms();
+        let ids: Vec<_> = (0..3).map(|_| ulid.next()).collect();
+        let after = now_ms();
+        for id in &ids {
+            assert_eq!(id.len(), 26);
+            assert!((before..=after).contains(&((decode_ulid(id) >> 80) as u64)));
+        }
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn ulids_wait_for_the_next_millisecond_once_the_random_part_runs_out() {
+        let mut ulid = Ulid::default();
+        let ms = ulid.clock.now() as u128;
+        ulid.last = ms << 80 | ULID_RANDOM;
+        let next = decode_ulid(&ulid.next());
+        assert!(next >> 80 > ms);
+        assert!(next > ms << 80 | ULID_RANDOM);
+        // ids made later in that millisecond still sort above
+        assert!(decode_ulid(&ulid.next()) > next);
+    }
 }
@@ -1,4 +1,5 @@
 use anyhow::Context;
+use num_bigint::BigInt;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::io::{BufRead, StdoutLock, Write};
 
@@ -61,6 +62,101 @@ enum InitPayload {
     InitOk,
 }
 
+// requests understood by Maelstrom's key-value services (seq-kv, lin-kv and lww-kv). they answer
+// with read_ok { value }, write_ok, cas_ok or an error.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum KvRequest<V> {
+    Read {
+        key: String,
+    },
+    Write {
+        key: String,
+        value: V,
+    },
+    Cas {
+        key: String,
+        from: V,
+        to: V,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+}
+
+// error codes from https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors
+pub mod error_code {
+    pub const TIMEOUT: usize = 0;
+    pub const NODE_NOT_FOUND: usize = 1;
+    pub const NOT_SUPPORTED: usize = 10;
+    pub const TEMPORARILY_UNAVAILABLE: usize = 11;
+    pub const MALFORMED_REQUEST: usize = 12;
+    pub const CRASH: usize = 13;
+    pub const ABORT: usize = 14;
+    pub const KEY_DOES_NOT_EXIST: usize = 20;
+    pub const KEY_ALREADY_EXISTS: usize = 21;
+    pub const PRECONDITION_FAILED: usize = 22;
+    pub const TXN_CONFLICT: usize = 30;
+}
+
+// an arbitrary-precision integer as it appears on the wire: a JSON number while it fits in an
+// i64, a decimal string beyond that. serde_json can't represent larger numbers without losing
+// precision, so both forms are accepted when reading.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Integer(pub BigInt);
+
+impl Integer {
+    // reads an integer out of a client request, where anything but an exact integer is malformed.
+    pub fn from_json(value: &serde_json::Value) -> Option<Self> {
+        match value {
+            serde_json::Value::Number(n) => n
+                .as_i64()
+                .map(BigInt::from)
+                .or_else(|| n.as_u64().map(BigInt::from))
+                .map(Integer),
+            serde_json::Value::String(s) => s.parse().ok().map(Integer),
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for Integer {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match i64::try_from(&self.0) {
+            Ok(n) => serializer.serialize_i64(n),
+            Err(_) => serializer.collect_str(&self.0),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Integer {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct IntegerVisitor;
+
+        impl serde::de::Visitor<'_> for IntegerVisitor {
+            type Value = Integer;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an integer or a decimal string")
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Integer, E> {
+                Ok(Integer(v.into()))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Integer, E> {
+                Ok(Integer(v.into()))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Integer, E> {
+                v.parse().map(Integer).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(IntegerVisitor)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Init {
     pub node_id: String,
@@ -0,0 +1,225 @@
+// not every test uses all of the cluster
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc::{channel, Receiver, Sender},
+    time::{Duration, Instant},
+};
+
+// kafka nodes running as their own processes, with the messages between them routed the way
+// Maelstrom does. lin-kv is served here too.
+pub struct Cluster {
+    pub ids: Vec<String>,
+    env: Vec<(String, String)>,
+    children: HashMap<String, Child>,
+    stdins: HashMap<String, ChildStdin>,
+    // every message the nodes write
+    tx: Sender<Value>,
+    rx: Receiver<Value>,
+    pub msg_id: usize,
+    lin_kv: HashMap<String, Value>,
+    // messages to nodes, lin-kv's answers included, that the test holds back instead of
+    // delivering
+    pub hold: fn(&Value) -> bool,
+    pub held: Vec<Value>,
+    pub delivered: Vec<Value>,
+}
+
+impl Cluster {
+    pub fn start(count: usize) -> Self {
+        Self::start_with(count, &[])
+    }
+
+    // starts the nodes with the given environment variables set.
+    pub fn start_with(count: usize, env: &[(&str, &str)]) -> Self {
+        let ids: Vec<_> = (0..count).map(|i| format!("n{}", i)).collect();
+        let (tx, rx) = channel();
+        let mut cluster = Self {
+            ids: ids.clone(),
+            env: env
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            children: HashMap::new(),
+            stdins: HashMap::new(),
+            tx,
+            rx,
+            msg_id: 0,
+            lin_kv: HashMap::new(),
+            hold: |_| false,
+            held: Vec::new(),
+            delivered: Vec::new(),
+        };
+        for id in &ids {
+            cluster.spawn(id);
+        }
+        cluster
+    }
+
+    // starts the node's process and initializes it.
+    fn spawn(&mut self, id: &str) {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_kafka"))
+            .envs(self.env.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("start kafka");
+        self.stdins
+            .insert(id.to_string(), child.stdin.take().unwrap());
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let tx = self.tx.clone();
+        std::thread::spawn(move || {
+            for line in stdout.lines() {
+                let Ok(line) = line else { break };
+                if tx.send(serde_json::from_str(&line).unwrap()).is_err() {
+                    break;
+                }
+            }
+        });
+        self.children.insert(id.to_string(), child);
+        let init = json!({"type": "init", "node_id": id, "node_ids": self.ids});
+        assert_eq!(self.request(id, init)["type"], "init_ok");
+    }
+
+    // kills the node and starts it again, with whatever it kept in storage.
+    pub fn restart(&mut self, id: &str) {
+        let mut child = self.children.remove(id).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        self.spawn(id);
+    }
+
+    pub fn write(&mut self, message: &Value) {
+        let dest = message["dest"].as_str().unwrap();
+        writeln!(self.stdins.get_mut(dest).unwrap(), "{}", message).unwrap();
+    }
+
+    // delivers what the nodes send each other until the reply to the client shows up.
+    pub fn route(&mut self, in_reply_to: Option<usize>, until: Instant) -> Option<Value> {
+        while Instant::now() < until {
+            let Ok(mut message) = self.rx.recv_timeout(Duration::from_millis(10)) else {
+                continue;
+            };
+            if message["dest"] == "lin-kv" {
+                message = self.serve_lin_kv(&message);
+            } else if !message["dest"].as_str().unwrap().starts_with('n') {
+                let body = &message["body"];
+                if in_reply_to.is_some_and(|id| body["in_reply_to"] == json!(id)) {
+                    return Some(body.clone());
+                }
+                continue;
+            }
+            if (self.hold)(&message) {
+                self.held.push(message);
+                continue;
+            }
+            self.write(&message);
+            self.delivered.push(message);
+        }
+        None
+    }
+
+    // answers a request to lin-kv the way Maelstrom's does.
+    fn serve_lin_kv(&mut self, request: &Value) -> Value {
+        let body = &request["body"];
+        let key = body["key"].as_str().unwrap().to_string();
+        let reply = match (body["type"].as_str().unwrap(), self.lin_kv.get(&key)) {
+            ("read", Some(value)) => json!({"type": "read_ok", "value": value}),
+            ("write", _) => {
+                self.lin_kv.insert(key, body["value"].clone());
+                json!({"type": "write_ok"})
+            }
+            ("cas", Some(value)) if *value != body["from"] => {
+                json!({"type": "error", "code": 22, "text": "the value has changed"})
+            }
+            ("cas", None) if body["create_if_not_exists"] != true => {
+                json!({"type": "error", "code": 20, "text": "no such key"})
+            }
+            ("cas", _) => {
+                self.lin_kv.insert(key, body["to"].clone());
+                json!({"type": "cas_ok"})
+            }
+            _ => json!({"type": "error", "code": 20, "text": "no such key"}),
+        };
+        let mut reply = json!({"src": "lin-kv", "dest": request["src"], "body": reply});
+        reply["body"]["in_reply_to"] = body["msg_id"].clone();
+        reply
+    }
+
+    pub fn request(&mut self, node: &str, body: Value) -> Value {
+        let id = self.submit(node, body);
+        let until = Instant::now() + Duration::from_secs(10);
+        self.route(Some(id), until).expect("the node answers")
+    }
+
+    // sends a client request without waiting for the answer, returning its msg_id.
+    pub fn submit(&mut self, node: &str, mut body: Value) -> usize {
+        self.msg_id += 1;
+        body["msg_id"] = json!(self.msg_id);
+        let message = json!({"src": "c1", "dest": node, "body": body});
+        self.write(&message);
+        self.msg_id
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        self.route(None, Instant::now() + duration);
+    }
+
+    pub fn release(&mut self) {
+        self.hold = |_| false;
+        for message in std::mem::take(&mut self.held) {
+            self.write(&message);
+            self.delivered.push(message);
+        }
+    }
+
+    // the first of the keys k0, k1, ... that the node leads, picked the same way the nodes do
+    pub fn key_led_by(&self, node: &str) -> String {
+        (0..)
+            .map(|i| format!("k{}", i))
+            .find(|key| {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                self.ids[hasher.finish() as usize % self.ids.len()] == node
+            })
+            .unwrap()
+    }
+
+    pub fn send(&mut self, node: &str, key: &str, msg: usize) -> Value {
+        self.request(node, json!({"type": "send", "key": key, "msg": msg}))
+    }
+
+    pub fn send_batch(&mut self, node: &str, msgs: &[(&str, usize)], atomic: bool) -> Value {
+        self.request(
+            node,
+            json!({"type": "send_batch", "msgs": msgs, "atomic": atomic}),
+        )
+    }
+
+    pub fn poll(&mut self, node: &str, keys: &[&str]) -> HashMap<String, Vec<(usize, usize)>> {
+        let offsets: Vec<_> = keys.iter().map(|key| (*key, 0)).collect();
+        let reply = self.poll_from(node, &offsets);
+        assert_eq!(reply["type"], "poll_ok", "{}", reply);
+        serde_json::from_value(reply["msgs"].clone()).unwrap()
+    }
+
+    // polls the keys from the given offsets, returning the whole reply.
+    pub fn poll_from(&mut self, node: &str, offsets: &[(&str, usize)]) -> Value {
+        let offsets: HashMap<_, _> = offsets.iter().copied().collect();
+        self.request(node, json!({"type": "poll", "offsets": offsets}))
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for child in self.children.values_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
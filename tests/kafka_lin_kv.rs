@@ -0,0 +1,68 @@
+mod common;
+
+use common::Cluster;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+const LIN_KV: [(&str, &str); 1] = [("KAFKA_MODE", "lin-kv")];
+
+fn offset(reply: &Value) -> usize {
+    assert_eq!(reply["type"], "send_ok", "{}", reply);
+    reply["offset"].as_u64().unwrap() as usize
+}
+
+#[test]
+fn sends_take_offsets_in_turn() {
+    let mut cluster = Cluster::start_with(2, &LIN_KV);
+    assert_eq!(offset(&cluster.send("n0", "a", 1)), 0);
+    assert_eq!(offset(&cluster.send("n1", "a", 2)), 1);
+    assert_eq!(offset(&cluster.send("n0", "a", 3)), 2);
+    cluster.run_for(Duration::from_millis(300));
+    for node in ["n0", "n1"] {
+        assert_eq!(
+            cluster.poll(node, &["a"])["a"],
+            vec![(0, 1), (1, 2), (2, 3)]
+        );
+    }
+}
+
+#[test]
+fn claims_whose_answer_was_lost_are_read_back() {
+    let mut cluster = Cluster::start_with(2, &LIN_KV);
+    // n0 takes offset 0 without hearing that it did, and n1 takes the next one meanwhile
+    cluster.hold = |m| m["dest"] == "n0" && m["body"]["type"] == "cas_ok";
+    let first = cluster.submit("n0", json!({"type": "send", "key": "a", "msg": 1}));
+    cluster.run_for(Duration::from_millis(200));
+    assert!(!cluster.held.is_empty());
+    assert_eq!(offset(&cluster.send("n1", "a", 2)), 1);
+
+    let reply = cluster
+        .route(Some(first), Instant::now() + Duration::from_secs(5))
+        .expect("n0 answers once it finds out the offset is its own");
+    assert_eq!(offset(&reply), 0);
+    cluster.run_for(Duration::from_millis(300));
+    for node in ["n0", "n1"] {
+        assert_eq!(cluster.poll(node, &["a"])["a"], vec![(0, 1), (1, 2)]);
+    }
+}
+
+#[test]
+fn retries_waiting_for_an_offset_time_out() {
+    let mut cluster = Cluster::start_with(1, &LIN_KV);
+    // lin-kv doesn't answer for a while
+    cluster.hold = |m| m["src"] == "lin-kv";
+    let send = json!({"type": "send", "key": "a", "msg": 1, "producer": "p", "seq": 0});
+    let first = cluster.submit("n0", send.clone());
+    let retry = cluster.submit("n0", send);
+    let reply = cluster
+        .route(Some(retry), Instant::now() + Duration::from_secs(5))
+        .expect("the retry gets an answer");
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 0);
+
+    cluster.release();
+    let reply = cluster
+        .route(Some(first), Instant::now() + Duration::from_secs(5))
+        .expect("the send goes on");
+    assert_eq!(offset(&reply), 0);
+}
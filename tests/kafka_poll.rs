@@ -0,0 +1,72 @@
+mod common;
+
+use common::Cluster;
+use serde_json::Value;
+use std::collections::HashMap;
+
+// (offset, msg) pairs by key
+type Polled = HashMap<String, Vec<(usize, usize)>>;
+
+// the messages and continuation offsets of a poll_ok
+fn polled(reply: &Value) -> (Polled, HashMap<String, usize>) {
+    assert_eq!(reply["type"], "poll_ok", "{}", reply);
+    let msgs = serde_json::from_value(reply["msgs"].clone()).unwrap();
+    let next_offsets = match reply.get("next_offsets") {
+        Some(next_offsets) => serde_json::from_value(next_offsets.clone()).unwrap(),
+        None => HashMap::new(),
+    };
+    (msgs, next_offsets)
+}
+
+#[test]
+fn polls_beyond_the_end_find_nothing() {
+    let mut cluster = Cluster::start(1);
+    cluster.send_batch("n0", &[("a", 1), ("a", 2), ("a", 3)], false);
+
+    let (msgs, next_offsets) = polled(&cluster.poll_from("n0", &[("a", 10), ("b", 0)]));
+    assert_eq!(msgs["a"], vec![]);
+    assert!(msgs.get("b").is_none_or(Vec::is_empty));
+    assert!(next_offsets.is_empty());
+}
+
+#[test]
+fn long_logs_are_polled_in_pages() {
+    let mut cluster = Cluster::start(1);
+    let batch: Vec<_> = (0..150).map(|msg| ("a", msg)).collect();
+    cluster.send_batch("n0", &batch, false);
+
+    let (msgs, next_offsets) = polled(&cluster.poll_from("n0", &[("a", 0)]));
+    let first: Vec<_> = (0..100).map(|o| (o, o)).collect();
+    assert_eq!(msgs["a"], first);
+    assert_eq!(next_offsets["a"], 100);
+
+    let (msgs, next_offsets) = polled(&cluster.poll_from("n0", &[("a", 100)]));
+    let rest: Vec<_> = (100..150).map(|o| (o, o)).collect();
+    assert_eq!(msgs["a"], rest);
+    assert!(next_offsets.is_empty());
+}
+
+#[test]
+fn responses_are_capped_across_keys() {
+    let mut cluster = Cluster::start(1);
+    let keys: Vec<_> = (0..12).map(|i| format!("k{}", i)).collect();
+    let batch: Vec<_> = keys
+        .iter()
+        .flat_map(|key| (0..100).map(move |msg| (key.as_str(), msg)))
+        .collect();
+    cluster.send_batch("n0", &batch, false);
+
+    let offsets: Vec<_> = keys.iter().map(|key| (key.as_str(), 0)).collect();
+    let (msgs, next_offsets) = polled(&cluster.poll_from("n0", &offsets));
+    let total: usize = msgs.values().map(Vec::len).sum();
+    assert_eq!(total, 1000);
+    // every key left out, or cut short, says where to go on from
+    for key in &keys {
+        let got = msgs.get(key).map_or(0, Vec::len);
+        if got < 100 {
+            assert_eq!(next_offsets[key], got, "{} got {} msgs", key, got);
+        } else {
+            assert!(!next_offsets.contains_key(key));
+        }
+    }
+}
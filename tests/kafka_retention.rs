@@ -0,0 +1,55 @@
+mod common;
+
+use common::Cluster;
+use serde_json::json;
+use std::time::Duration;
+
+// how long it takes a node to get around to dropping what retention lets go of
+const RETAIN_WAIT: Duration = Duration::from_millis(500);
+
+#[test]
+fn polls_below_the_retained_start_are_out_of_range() {
+    let mut cluster = Cluster::start_with(1, &[("KAFKA_RETENTION", "committed")]);
+    let batch: Vec<_> = (0..5).map(|msg| ("a", msg)).collect();
+    cluster.send_batch("n0", &batch, false);
+    let reply = cluster.request("n0", json!({"type": "commit_offsets", "offsets": {"a": 3}}));
+    assert_eq!(reply["type"], "commit_offsets_ok");
+    cluster.run_for(RETAIN_WAIT);
+
+    let reply = cluster.poll_from("n0", &[("a", 2)]);
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 1000);
+    let reply = cluster.poll_from("n0", &[("a", 3)]);
+    assert_eq!(reply["msgs"]["a"], json!([[3, 3], [4, 4]]));
+}
+
+#[test]
+fn superseded_msgs_are_compacted_away() {
+    let mut cluster = Cluster::start_with(1, &[("KAFKA_RETENTION", "compact")]);
+    let batch = [("a", 1), ("a", 2), ("a", 1), ("a", 3), ("a", 2)];
+    cluster.send_batch("n0", &batch, false);
+    cluster.run_for(RETAIN_WAIT);
+
+    // only the latest offset of each msg is left, and offsets stay where they were
+    assert_eq!(
+        cluster.poll("n0", &["a"])["a"],
+        vec![(2, 1), (3, 3), (4, 2)]
+    );
+}
+
+#[test]
+fn max_bytes_caps_how_many_entries_a_log_keeps() {
+    // room for 4 entries of an offset and a msg, and part of another
+    let max_bytes = format!("max-bytes={}", 4 * 16 + 8);
+    let mut cluster = Cluster::start_with(1, &[("KAFKA_RETENTION", max_bytes.as_str())]);
+    let batch: Vec<_> = (0..10).map(|msg| ("a", msg)).collect();
+    cluster.send_batch("n0", &batch, false);
+    cluster.run_for(RETAIN_WAIT);
+
+    let reply = cluster.poll_from("n0", &[("a", 5)]);
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 1000);
+    let kept: Vec<_> = (6..10).map(|o| (o, o)).collect();
+    let reply = cluster.poll_from("n0", &[("a", 6)]);
+    assert_eq!(reply["msgs"]["a"], json!(kept));
+}
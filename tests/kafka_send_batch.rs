@@ -0,0 +1,171 @@
+mod common;
+
+use common::Cluster;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+fn offsets(reply: &Value) -> Vec<usize> {
+    assert_eq!(reply["type"], "send_batch_ok", "{}", reply);
+    serde_json::from_value(reply["offsets"].clone()).unwrap()
+}
+
+#[test]
+fn batch_entries_get_offsets_of_their_own() {
+    let mut cluster = Cluster::start(1);
+    let msgs = [("a", 1), ("b", 2), ("a", 3), ("a", 4), ("b", 5)];
+    let reply = cluster.send_batch("n0", &msgs, false);
+    assert_eq!(offsets(&reply), vec![0, 0, 1, 2, 1]);
+
+    let polled = cluster.poll("n0", &["a", "b"]);
+    assert_eq!(polled["a"], vec![(0, 1), (1, 3), (2, 4)]);
+    assert_eq!(polled["b"], vec![(0, 2), (1, 5)]);
+}
+
+#[test]
+fn batch_parts_go_to_the_leaders_of_their_keys() {
+    let mut cluster = Cluster::start(3);
+    let keys: Vec<_> = ["n0", "n1", "n2"]
+        .iter()
+        .map(|n| cluster.key_led_by(n))
+        .collect();
+    let msgs: Vec<_> = keys.iter().map(|key| (key.as_str(), 7)).collect();
+    let reply = cluster.send_batch("n0", &msgs, false);
+    assert_eq!(offsets(&reply), vec![0, 0, 0]);
+
+    for leader in ["n1", "n2"] {
+        let forwarded = cluster
+            .delivered
+            .iter()
+            .any(|m| m["src"] == "n0" && m["dest"] == leader && m["body"]["type"] == "send_batch");
+        assert!(forwarded, "n0 didn't hand {} its part", leader);
+    }
+    for (key, leader) in keys.iter().zip(["n0", "n1", "n2"]) {
+        assert_eq!(cluster.poll(leader, &[key])[key], vec![(0, 7)]);
+    }
+}
+
+#[test]
+fn atomic_batches_show_up_all_at_once() {
+    let mut cluster = Cluster::start(3);
+    let keys: Vec<_> = ["n0", "n1", "n2"]
+        .iter()
+        .map(|n| cluster.key_led_by(n))
+        .collect();
+    let keys: Vec<_> = keys.iter().map(String::as_str).collect();
+    let msgs: Vec<_> = keys.iter().map(|key| (*key, 1)).collect();
+
+    // n0 commits its own part right away, n2 only once the commit reaches it
+    cluster.hold = |m| m["dest"] == "n2" && m["body"]["type"] == "commit";
+    cluster.msg_id += 1;
+    let body =
+        json!({"type": "send_batch", "msgs": msgs, "atomic": true, "msg_id": cluster.msg_id});
+    cluster.write(&json!({"src": "c1", "dest": "n0", "body": body}));
+    cluster.run_for(Duration::from_millis(500));
+    assert!(!cluster.held.is_empty());
+    for node in ["n0", "n1", "n2"] {
+        let polled = cluster.poll(node, &keys);
+        assert!(
+            polled.values().all(Vec::is_empty),
+            "{} shows part of the batch",
+            node
+        );
+    }
+
+    cluster.release();
+    cluster.run_for(Duration::from_millis(500));
+    for node in ["n0", "n1", "n2"] {
+        let polled = cluster.poll(node, &keys);
+        for key in &keys {
+            assert_eq!(polled[*key], vec![(0, 1)], "{} misses {}", node, key);
+        }
+    }
+}
+
+#[test]
+fn aborted_atomic_batches_leave_nothing_behind() {
+    let mut cluster = Cluster::start(3);
+    let keys: Vec<_> = ["n0", "n1", "n2"]
+        .iter()
+        .map(|n| cluster.key_led_by(n))
+        .collect();
+    let keys: Vec<_> = keys.iter().map(String::as_str).collect();
+    let msgs: Vec<_> = keys.iter().map(|key| (*key, 1)).collect();
+
+    // n2 never hears of the batch in time
+    cluster.hold = |m| m["dest"] == "n2" && m["body"]["type"] == "prepare";
+    let reply = cluster.send_batch("n0", &msgs, true);
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 14);
+
+    // n2 turns down the prepare that arrives after the abort, and holds nothing for it
+    cluster.run_for(Duration::from_millis(300));
+    let late = cluster.held[0]["body"]["msg_id"].clone();
+    cluster.release();
+    cluster.run_for(Duration::from_millis(300));
+    let refused = cluster.delivered.iter().any(|m| {
+        m["src"] == "n2" && m["body"]["in_reply_to"] == late && m["body"]["type"] == "error"
+    });
+    assert!(refused, "n2 prepared a part of an aborted batch");
+
+    let reply = cluster.send_batch("n0", &[(keys[2], 2)], false);
+    assert_eq!(offsets(&reply), vec![0]);
+    cluster.run_for(Duration::from_millis(300));
+    for node in ["n0", "n1", "n2"] {
+        let polled = cluster.poll(node, &keys);
+        assert!(polled.get(keys[0]).is_none_or(Vec::is_empty));
+        assert!(polled.get(keys[1]).is_none_or(Vec::is_empty));
+        assert_eq!(polled[keys[2]], vec![(0, 2)]);
+    }
+}
+
+#[test]
+fn atomic_batches_in_lin_kv_show_up_all_at_once() {
+    let mut cluster = Cluster::start_with(2, &[("KAFKA_MODE", "lin-kv")]);
+    // n1 gets the first entry, but not the second
+    cluster.hold = |m| {
+        m["dest"] == "n1" && m["body"]["type"] == "replicate" && m["body"]["entries"][0][1] == 1
+    };
+    let reply = cluster.send_batch("n0", &[("a", 1), ("a", 2)], true);
+    assert_eq!(offsets(&reply), vec![0, 1]);
+    cluster.run_for(Duration::from_millis(300));
+    assert!(!cluster.held.is_empty());
+    assert_eq!(cluster.poll("n1", &["a"])["a"], vec![]);
+
+    cluster.release();
+    cluster.run_for(Duration::from_millis(300));
+    for node in ["n0", "n1"] {
+        assert_eq!(cluster.poll(node, &["a"])["a"], vec![(0, 1), (1, 2)]);
+    }
+}
+
+#[test]
+fn parts_of_batches_their_coordinator_forgot_are_aborted() {
+    let mut cluster = Cluster::start(3);
+    let keys: Vec<_> = ["n1", "n2"].iter().map(|n| cluster.key_led_by(n)).collect();
+    let keys: Vec<_> = keys.iter().map(String::as_str).collect();
+    let msgs: Vec<_> = keys.iter().map(|key| (*key, 1)).collect();
+
+    // both leaders prepare their part, and n0 restarts before it hears that they did
+    cluster.hold = |m| m["dest"] == "n0" && m["body"]["type"] == "prepare_ok";
+    cluster.submit(
+        "n0",
+        json!({"type": "send_batch", "msgs": msgs, "atomic": true}),
+    );
+    cluster.run_for(Duration::from_millis(300));
+    for leader in ["n1", "n2"] {
+        assert!(cluster.held.iter().any(|m| m["src"] == leader));
+    }
+    cluster.hold = |_| false;
+    cluster.held.clear();
+    cluster.restart("n0");
+    let reply = cluster.send("n1", keys[0], 2);
+    assert_eq!(reply["type"], "error", "{} isn't held", keys[0]);
+
+    // once they have waited long enough for a decision they ask n0, which knows nothing of it
+    cluster.run_for(Duration::from_secs(3));
+    for (key, leader) in keys.iter().zip(["n1", "n2"]) {
+        let reply = cluster.send(leader, key, 2);
+        assert_eq!(reply["type"], "send_ok", "{}", reply);
+        assert_eq!(reply["offset"], 0);
+    }
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use common::Cluster;
+use serde_json::json;
+use std::process::{Command, Stdio};
+
+#[test]
+fn commits_survive_a_restart() {
+    let dir = std::env::temp_dir().join(format!("kafka-storage-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let storage = [
+        ("KAFKA_STORAGE", "segments"),
+        ("KAFKA_STORAGE_DIR", dir.to_str().unwrap()),
+    ];
+    let mut cluster = Cluster::start_with(1, &storage);
+    cluster.send_batch("n0", &[("a", 1), ("a", 2), ("b", 3)], false);
+    let reply = cluster.request(
+        "n0",
+        json!({"type": "commit_offsets", "offsets": {"a": 1, "b": 0}}),
+    );
+    assert_eq!(reply["type"], "commit_offsets_ok");
+
+    cluster.restart("n0");
+    let reply = cluster.request(
+        "n0",
+        json!({"type": "list_committed_offsets", "keys": ["a", "b"]}),
+    );
+    assert_eq!(reply["offsets"], json!({"a": 1, "b": 0}));
+    assert_eq!(cluster.poll("n0", &["a"])["a"], vec![(0, 1), (1, 2)]);
+
+    // commits taken after the journal was condensed on the first restart count too
+    let reply = cluster.request("n0", json!({"type": "commit_offsets", "offsets": {"b": 0}}));
+    assert_eq!(reply["type"], "commit_offsets_ok");
+    let reply = cluster.request(
+        "n0",
+        json!({"type": "commit_offsets", "offsets": {"a": 1}, "group": "g"}),
+    );
+    assert_eq!(reply["type"], "commit_offsets_ok");
+    cluster.restart("n0");
+    let reply = cluster.request(
+        "n0",
+        json!({"type": "list_committed_offsets", "keys": ["a", "b"]}),
+    );
+    assert_eq!(reply["offsets"], json!({"a": 1, "b": 0}));
+    let reply = cluster.request(
+        "n0",
+        json!({"type": "list_committed_offsets", "keys": ["a", "b"], "group": "g"}),
+    );
+    assert_eq!(reply["offsets"], json!({"a": 1}));
+    drop(cluster);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn segments_storage_needs_a_directory() {
+    let status = Command::new(env!("CARGO_BIN_EXE_kafka"))
+        .env("KAFKA_STORAGE", "segments")
+        .env_remove("KAFKA_STORAGE_DIR")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .expect("run kafka");
+    assert!(!status.success());
+}
+
+#[test]
+fn retries_from_before_a_restart_get_the_first_offset() {
+    let dir = std::env::temp_dir().join(format!("kafka-producers-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let storage = [
+        ("KAFKA_STORAGE", "segments"),
+        ("KAFKA_STORAGE_DIR", dir.to_str().unwrap()),
+    ];
+    let mut cluster = Cluster::start_with(1, &storage);
+    let send = json!({"type": "send", "key": "a", "msg": 7, "producer": "p", "seq": 0});
+    let reply = cluster.request("n0", send.clone());
+    assert_eq!(reply["offset"], 0);
+    cluster.send("n0", "a", 8);
+
+    cluster.restart("n0");
+    let reply = cluster.request("n0", send);
+    assert_eq!(reply["type"], "send_ok", "{}", reply);
+    assert_eq!(reply["offset"], 0);
+    assert_eq!(cluster.poll("n0", &["a"])["a"], vec![(0, 7), (1, 8)]);
+    drop(cluster);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
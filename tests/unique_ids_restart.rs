@@ -0,0 +1,93 @@
+use serde_json::{json, Value};
+use std::{
+    collections::HashSet,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+};
+
+// a unique-ids node running as its own process, talked to the way Maelstrom does.
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    msg_id: usize,
+}
+
+impl Process {
+    fn start(state_dir: Option<&Path>) -> Self {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_unique-ids"));
+        command.env("UNIQUE_IDS_MODE", "legacy");
+        if let Some(state_dir) = state_dir {
+            command.env("UNIQUE_IDS_STATE_DIR", state_dir);
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("start unique-ids");
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut process = Self {
+            child,
+            stdin,
+            stdout,
+            msg_id: 0,
+        };
+        let init_ok = process.request(json!({"type": "init", "node_id": "n1", "node_ids": ["n1"]}));
+        assert_eq!(init_ok["type"], "init_ok");
+        process
+    }
+
+    fn request(&mut self, mut body: Value) -> Value {
+        self.msg_id += 1;
+        body["msg_id"] = json!(self.msg_id);
+        let message = json!({"src": "c1", "dest": "n1", "body": body});
+        writeln!(self.stdin, "{}", message).unwrap();
+        let mut line = String::new();
+        self.stdout.read_line(&mut line).unwrap();
+        let reply: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(reply["body"]["in_reply_to"], json!(self.msg_id));
+        reply["body"].clone()
+    }
+
+    fn generate(&mut self, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| {
+                let reply = self.request(json!({"type": "generate"}));
+                assert_eq!(reply["type"], "generate_ok");
+                reply["id"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    fn kill(mut self) {
+        self.child.kill().unwrap();
+        self.child.wait().unwrap();
+    }
+}
+
+#[test]
+fn ids_stay_unique_across_restarts() {
+    let state_dir = std::env::temp_dir().join(format!("unique-ids-restart-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&state_dir);
+
+    let mut seen = HashSet::new();
+    // stop in the middle of a lease, right at its end and after several of them
+    for count in [10, 990, 2500, 1] {
+        let mut process = Process::start(Some(&state_dir));
+        for id in process.generate(count) {
+            assert!(seen.insert(id.clone()), "{} was handed out twice", id);
+        }
+        process.kill();
+    }
+
+    std::fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[test]
+fn legacy_ids_work_without_a_state_directory() {
+    let mut process = Process::start(None);
+    assert_eq!(process.generate(3), vec!["n1-0", "n1-1", "n1-2"]);
+    process.kill();
+}